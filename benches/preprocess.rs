@@ -0,0 +1,84 @@
+// Times turning a frame into the model's input, against the original Lanczos3 and per-pixel
+// indexing implementation.
+//
+//   cargo bench --bench preprocess [-- screenshot.png]
+//
+// Without an image a 1920x1080 RGBA frame is generated.
+
+#[path = "../src/preprocess.rs"]
+mod preprocess;
+
+use imageproc::image::{self, imageops, DynamicImage, Rgb, RgbImage, Rgba, RgbaImage};
+use ndarray::Array;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const INPUT_SIZE: (u32, u32) = (640, 640);
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let image = match std::env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(path) => image::open(&path).expect("Failed to load the image"),
+        None => DynamicImage::ImageRgba8(RgbaImage::from_fn(1920, 1080, |x, y| {
+            let light = ((x / 100) + (y / 100)) % 2 == 0;
+            Rgba([if light { 240 } else { 118 }, 150, (x % 256) as u8, 255])
+        })),
+    };
+    println!(
+        "{}x{} frame, {} iterations",
+        image.width(),
+        image.height(),
+        ITERATIONS
+    );
+
+    let original = time(|| {
+        black_box(original_process_image(&image, INPUT_SIZE));
+    });
+    println!("original: {:8.2} ms", original.as_secs_f64() * 1000.0);
+
+    let mut input = vec![0.0; 3 * INPUT_SIZE.0 as usize * INPUT_SIZE.1 as usize];
+    let fast = time(|| {
+        black_box(preprocess::letterbox_into(&image, INPUT_SIZE, &mut input));
+    });
+    println!(
+        "fast:     {:8.2} ms ({:.1}x)",
+        fast.as_secs_f64() * 1000.0,
+        original.as_secs_f64() / fast.as_secs_f64()
+    );
+}
+
+/// Mean duration of `f` after a warm-up run.
+fn time(mut f: impl FnMut()) -> Duration {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn original_process_image(img: &DynamicImage, input_size: (u32, u32)) -> Vec<f32> {
+    let (target_w, target_h) = input_size;
+    let scale = (target_w as f32 / img.width() as f32).min(target_h as f32 / img.height() as f32);
+    let new_w = ((img.width() as f32 * scale) as u32).min(target_w);
+    let new_h = ((img.height() as f32 * scale) as u32).min(target_h);
+    let resized = img
+        .resize_exact(new_w, new_h, imageops::FilterType::Lanczos3)
+        .to_rgb8();
+    let mut padded = RgbImage::new(target_w, target_h);
+    imageops::overlay(
+        &mut padded,
+        &resized,
+        ((target_w - new_w) / 2).into(),
+        ((target_h - new_h) / 2).into(),
+    );
+
+    let mut input = Array::zeros((1, 3, target_h as usize, target_w as usize));
+    for (x, y, pixel) in padded.enumerate_pixels() {
+        let Rgb([r, g, b]) = *pixel;
+        input[[0, 0, y as usize, x as usize]] = (r as f32) / 255.;
+        input[[0, 1, y as usize, x as usize]] = (g as f32) / 255.;
+        input[[0, 2, y as usize, x as usize]] = (b as f32) / 255.;
+    }
+    input.into_raw_vec_and_offset().0
+}
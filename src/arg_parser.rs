@@ -8,7 +8,8 @@ pub struct Args {
     #[command(subcommand)]
     pub command: Commands,
 
-    /// Specify the point of view for detection. Accepts "w" for white or "b" for black (default: w).
+    /// Specify the point of view for detection. Accepts "w" for white or "b" for black (default: w, or the side
+    /// --profile was calibrated with).
     /// This option is ignored if --no-fen is set to true AND if the input mode is standard input.
     #[arg(global=true,long, value_enum, default_value_t = Pov::W)]
     pub pov: Pov,
@@ -22,14 +23,67 @@ pub struct Args {
     #[arg(global = true, long, default_value_t = 0.7)]
     pub conf: f32,
 
+    /// Overlap above which non-max suppression drops the less confident of two boxes of a class, for models exported without NMS (default: 0.7).
+    #[arg(global = true, long, default_value_t = 0.7)]
+    pub iou: f32,
+
     /// Padding to add around the cropped chessboard detection before performing a refined detection (default: 0.1).
     #[arg(global = true, long, default_value_t = 0.1)]
     pub refined_padding: f32,
 
+    /// Use the board's box from the model as it is, instead of snapping it to the square grid found inside it
+    /// (default: false). The grid is only searched again when the box moves, which takes around 10 ms for a
+    /// 500 pixel board.
+    #[arg(global = true, long, default_value_t = false)]
+    pub no_grid_fit: bool,
+
     /// Path to the onnx model file for chessboard detection (default: "chess_detection.onnx").
     #[arg(global = true, long, default_value = "chess_detection.onnx")]
     pub model_path: String,
 
+    /// How the pieces on the squares are read once the board is found: from the detections, with the square
+    /// classifier given by --classifier-path, both averaged, or by matching the piece templates of --profile
+    /// (default: detection).
+    #[arg(global = true, long, value_enum, default_value_t = RecognizerKind::Detection)]
+    pub recognizer: RecognizerKind,
+
+    /// Path to the onnx model classifying single squares, used by --recognizer classifier and ensemble.
+    #[arg(global = true, long)]
+    pub classifier_path: Option<String>,
+
+    /// Board profile written by `calibrate`. The board is taken from it instead of being detected, so it has to
+    /// stay where it was calibrated, and so is the point of view unless --pov is given.
+    #[arg(global = true, long)]
+    pub profile: Option<String>,
+
+    /// Number of threads used to run the model, 0 lets ONNX Runtime decide (default: 2).
+    #[arg(global = true, long, default_value_t = 2)]
+    pub intra_threads: usize,
+
+    /// Number of threads used to run independent parts of the model in parallel, 0 runs them sequentially (default: 0).
+    #[arg(global = true, long, default_value_t = 0)]
+    pub inter_threads: usize,
+
+    /// How much ONNX Runtime optimises the model when loading it (default: all).
+    #[arg(global = true, long, value_enum, default_value_t = OptimizationLevel::All)]
+    pub optimization_level: OptimizationLevel,
+
+    /// Execution providers to try in order, comma separated. Providers missing from the ONNX Runtime build
+    /// are skipped with a warning, and the CPU is always the last resort (default: cpu).
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "cpu"
+    )]
+    pub execution_provider: Vec<ExecutionProvider>,
+
+    /// Save the optimised detection model to this path. Loading it with --model-path and --optimization-level
+    /// disable skips the optimisation on later starts. `serve` saves it from its first session only.
+    #[arg(global = true, long)]
+    pub save_optimized_model: Option<String>,
+
     /// Enables castling for white (default: false).
     #[arg(global = true, long, default_value_t = false)]
     pub castle_w: bool,
@@ -48,12 +102,66 @@ pub enum Pov {
     B,
 }
 
+/// Graph optimisation level of ONNX Runtime.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OptimizationLevel {
+    Disable,
+    Basic,
+    Extended,
+    All,
+}
+
+/// What reads the pieces on the squares.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RecognizerKind {
+    /// The pieces found by the detection model.
+    Detection,
+    /// A classifier run on every square.
+    Classifier,
+    /// The detections and the classifier, averaged.
+    Ensemble,
+    /// The squares compared with the piece templates of --profile. No model is loaded.
+    Template,
+}
+
+/// Hardware backend ONNX Runtime runs the model on.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionProvider {
+    Cpu,
+    Xnnpack,
+    Openvino,
+}
+
+/// How a move is performed on the board.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum MoveMode {
+    /// Click the source square, then the destination square.
+    Click,
+    /// Press on the source square, drag to the destination square and release.
+    Drag,
+}
+
+/// How the promotion piece is picked after a pawn reaches the last rank.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum PromotionMode {
+    /// Ask the user to promote manually and press enter.
+    Manual,
+    /// Detect the promotion dialog in the next screenshot and click the requested piece.
+    /// Falls back to manual if the dialog is not found.
+    Dialog,
+    /// Click N squares down the promotion file, where N is the piece's index in --promotion-order.
+    Offset,
+}
+
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] // parsed once
 pub enum Commands {
     /// Process an image file and print the detections and fen.
     Process {
         /// Path to the image file, or "-" to read from stdin.
         /// When reading from standard input, the first byte specifies the point of view (POV), the next 4 bytes indicate the image size, and the remaining bytes are the image data.
+        /// Frames starting with "CHST" use the versioned format instead, which also carries raw RGB/RGBA/BGRA pixels
+        /// and per-frame metadata (see the README).
         image_path: String,
 
         /// Don't attempt to extract FEN notation from the best chessboard detection (default: false).
@@ -65,6 +173,11 @@ pub enum Commands {
         #[arg(long, default_value_t = false)]
         print_detections: bool,
 
+        /// Print the piece and confidence read on every square, the board's confidence and the squares the reading
+        /// isn't sure about: what else they could be, or that they look occupied while read as empty (default: false).
+        #[arg(long, default_value_t = false)]
+        print_confidence: bool,
+
         /// Print only the best chessboard detection and its associated pieces for FEN extraction.
         /// This flag is ignored if --no-fen is true (default: false).
         #[arg(long, default_value_t = false)]
@@ -78,24 +191,70 @@ pub enum Commands {
         /// This option is ignored if a file path is not "-" (default: false).
         #[arg(long, default_value_t = false)]
         dont_exit: bool,
+
+        /// Answer every image with a length-prefixed response frame (JSON header plus the annotated PNG when
+        /// --output-path is "-") instead of plain text, and report failed detections as error frames
+        /// instead of exiting. See the README for the format (default: false).
+        #[arg(long, default_value_t = false)]
+        framed: bool,
     },
 
     /// Play a game of chess for you as a bot.
     Play {
         /// Defines the command to capture a screenshot and pipe image data to stdout for Chust to process.
-        /// The image must be in a format supported by the "image" crate.
+        /// The image must be in a format supported by the "image" crate. `{output}` is replaced with the monitor index.
+        /// The command is split into words like a shell would (quotes and escapes work), unless --shell-commands is set.
         /// Default: `xcap` for Windows, Linux (X11), macOS, and `wlr-screencopy-unstable-v1` for Wayland (via wayland-client).
         #[arg(long)]
         screenshot_command: Option<String>,
 
         /// Specifies a command to simulate a mouse click at given coordinates.
         /// Use `{x}` and `{y}` as placeholders, which Chust replaces with actual coordinates before execution.
+        /// `{button}`, `{square}` (e.g. "e4", empty when not clicking a square) and `{output}` are also available.
         /// Default: `enigo` for Windows, Linux (X11), macOS, and `wlr-virtual-pointer-unstable-v1` for Wayland.
         ///
         /// Example: "some_tool click {x}, {y}"
         #[arg(long)]
         click_command: Option<String>,
 
+        /// Specifies a command to drag the mouse from one position to another, used when --move-mode is "drag".
+        /// Use `{from_x}`, `{from_y}`, `{to_x}` and `{to_y}` as placeholders, plus `{from_square}`, `{square}`
+        /// (the destination), `{button}` and `{output}`.
+        /// Default: the native backend (see --click-command).
+        ///
+        /// Example: "some_tool drag {from_x} {from_y} {to_x} {to_y}"
+        #[arg(long)]
+        drag_command: Option<String>,
+
+        /// Starts this command once and exchanges screenshot and click requests with it over stdin/stdout,
+        /// instead of running a command for every action. See the README for the protocol.
+        #[arg(long, conflicts_with_all = ["screenshot_command", "click_command", "drag_command"])]
+        helper_command: Option<String>,
+
+        /// Run the custom commands through the system shell (`sh -c`, `cmd /S /C` on Windows) instead of
+        /// splitting them into words. Placeholder values are quoted either way (default: false).
+        #[arg(long, default_value_t = false)]
+        shell_commands: bool,
+
+        /// Kill a custom command that runs for longer than this many seconds (default: 10).
+        #[arg(long, default_value_t = 10.0)]
+        command_timeout: f32,
+
+        /// How moves are made on the board: by clicking the source and destination squares,
+        /// or by dragging the piece from one to the other (default: click).
+        #[arg(long, value_enum, default_value_t = MoveMode::Click)]
+        move_mode: MoveMode,
+
+        /// How the promotion piece is chosen when Stockfish promotes a pawn (default: dialog, or offset when no model
+        /// is loaded as with --recognizer template).
+        #[arg(long, value_enum, default_value_t = PromotionMode::Dialog)]
+        promotion_mode: PromotionMode,
+
+        /// Order of the pieces in the promotion dialog, starting at the promotion square and going down the file.
+        /// Used by the "offset" promotion mode (default: "qnrb").
+        #[arg(long, default_value = "qnrb")]
+        promotion_order: String,
+
         /// Sets the delay (in seconds) before capturing another screenshot. (default: 0.5 seconds).
         #[arg(long, default_value_t = 0.5)]
         screenshot_delay: f32,
@@ -120,12 +279,143 @@ pub enum Commands {
         #[arg(long, default_value_t = false)]
         recheck_after_change: bool,
 
+        /// Number of frames a position has to be seen in before it is accepted. Each square is voted on
+        /// across these frames, so single misdetected frames are outvoted (default: 1).
+        #[arg(long, default_value_t = 1)]
+        stable_frames: usize,
+
+        /// Minimum time in milliseconds the frames voting on a position have to span (default: 0).
+        #[arg(long, default_value_t = 0)]
+        stable_ms: u64,
+
+        /// Discard the frames collected so far when the board moves by more than this fraction of its size
+        /// between frames, e.g. while the page scrolls or animates (default: 0.05).
+        #[arg(long, default_value_t = 0.05)]
+        max_board_shift: f32,
+
+        /// Skip detection when no part of the board changed in brightness by more than this (0-255) since the
+        /// last detected screenshot. 0 runs detection on every screenshot (default: 2.0).
+        #[arg(long, default_value_t = 2.0)]
+        change_threshold: f32,
+
+        /// Wait for another screenshot when the board is read with a confidence (0-1) below this, e.g. because
+        /// a square looks occupied but was read as empty. 0 acts on every board (default: 0.5).
+        #[arg(long, default_value_t = 0.5)]
+        min_confidence: f32,
+
         /// Specifies the delay (in seconds) between selecting a piece and clicking its destination.
+        /// In drag mode, this is the duration of the drag motion.
         /// This simulates a more human-like interaction with the board.
         /// Default: 0.1 seconds.
         #[arg(long, default_value_t = 0.1)]
         move_delay: f32,
+
+        /// After each move, the board is captured again until it settles and compared against the expected position.
+        /// If the board did not change, the move is retried up to this many times before thinking again; a lost
+        /// promotion click is retried on its own. Any other position is taken as the new current one.
+        /// Default: 2.
+        #[arg(long, default_value_t = 2)]
+        move_retries: u32,
+
+        /// Replay recorded frames instead of capturing the screen. Accepts a directory of images
+        /// (served in file name order) or a video file (frames are extracted with ffmpeg).
+        /// Clicks are written to --click-log instead of being performed.
+        #[arg(long)]
+        replay: Option<String>,
+
+        /// When replaying, serve frames on a timer (one frame every N seconds) instead of one frame per screenshot.
+        #[arg(long, requires = "replay")]
+        replay_interval: Option<f32>,
+
+        /// File that clicks are appended to when replaying (default: "clicks.log").
+        #[arg(long, default_value = "clicks.log")]
+        click_log: String,
+
+        /// Record every captured frame, its annotated detections, the extracted FEN and the engine's reply
+        /// into a timestamped session folder inside the given directory, with an `index.tsv` describing each entry.
+        #[arg(long)]
+        record_dir: Option<String>,
+
+        /// When recording, only save frames where the extracted FEN changed (default: false).
+        #[arg(long, default_value_t = false, requires = "record_dir")]
+        record_changed_frames_only: bool,
+
+        /// Click through a virtual pointer created with /dev/uinput instead of the display server (Linux only,
+        /// needs write access to /dev/uinput). Screenshots still come from the regular backend or --screenshot-command.
+        #[arg(long, default_value_t = false)]
+        uinput: bool,
+
+        /// Where the captured monitor sits on the desktop, as DESKTOP_WxDESKTOP_H[±X±Y]:MONITOR_WxMONITOR_H±X±Y
+        /// (e.g. "3840x1080:1920x1080+1920+0", or "3840x1080-1920+0:1920x1080-1920+0" for a monitor left of
+        /// the primary one). Read from the monitor layout when not given.
+        #[arg(long, requires = "uinput")]
+        uinput_calibration: Option<String>,
+    },
+
+    /// Turn a recorded game into a PGN and a timeline of the positions.
+    Video {
+        /// Video file (frames are extracted with ffmpeg) or a directory of frames sorted by file name.
+        source: String,
+
+        /// Frames per second to sample from a video file, or the rate a frame directory was captured at (default: 2).
+        #[arg(long, default_value_t = 2.0)]
+        fps: f32,
+
+        /// Number of frames a position has to be seen in before it counts. Each square is voted on
+        /// across these frames (default: 3).
+        #[arg(long, default_value_t = 3)]
+        stable_frames: usize,
+
+        /// Minimum video time in milliseconds the frames voting on a position have to span (default: 0).
+        #[arg(long, default_value_t = 0)]
+        stable_ms: u64,
+
+        /// Discard the frames collected so far when the board moves by more than this fraction of its size
+        /// between frames (default: 0.05).
+        #[arg(long, default_value_t = 0.05)]
+        max_board_shift: f32,
+
+        /// Write the PGN to this file instead of stdout.
+        #[arg(long)]
+        pgn: Option<String>,
+
+        /// Write the timeline (time in ms, FEN, moves) to this TSV file instead of printing it to stdout.
+        #[arg(long)]
+        timeline: Option<String>,
+    },
+
+    /// Serve board detection and FEN extraction over HTTP.
+    Serve {
+        /// Address to listen on (default: "127.0.0.1:8080").
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// Number of model sessions, which is also the number of requests processed at once (default: 1).
+        #[arg(long, default_value_t = 1)]
+        sessions: usize,
+
+        /// Largest accepted image in bytes (default: 33554432).
+        #[arg(long, default_value_t = 32 * 1024 * 1024)]
+        max_body_size: usize,
     },
+
+    /// Learn the board's exact position, orientation and piece templates from a screenshot of the
+    /// starting position, and save them as a profile for --profile.
+    Calibrate {
+        /// Path to the screenshot.
+        image_path: String,
+
+        /// Where to save the profile (default: "profile.json").
+        #[arg(long, default_value = "profile.json")]
+        output: String,
+
+        /// Position of the board on the screenshot as x,y,width,height in pixels, found by the model when not given.
+        #[arg(long, value_delimiter = ',')]
+        board: Option<Vec<f32>>,
+    },
+
+    /// List the screen capture and input capabilities available on this machine.
+    Doctor,
 }
 
 fn default_stockfish_path() -> &'static str {
@@ -0,0 +1,198 @@
+use crate::arg_parser::Args;
+use crate::chess_detection::{get_best_chessboard_match, ChessDetection, DetectionLevel};
+use crate::grid::fit_grid;
+use crate::profile::Profile;
+use crate::recognizer::{square_rect, Recognizer};
+use crate::template::{TemplateLibrary, TemplateRecognizer};
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{self, DynamicImage, Rgb, RgbImage};
+
+const START_PLACEMENT: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR";
+// smallest brightness difference (0-255) from the square's colour of a pixel belonging to a piece
+const PIECE_CONTRAST: u8 = 32;
+
+/// Learns the board from a screenshot of the starting position and saves it as a profile to
+/// `output`. The board is at `board` (x, y, width, height) when given, otherwise it's found by
+/// `chess_detector`.
+pub fn calibrate(
+    image_path: &str,
+    board: Option<Vec<f32>>,
+    output: &str,
+    args: &Args,
+    chess_detector: Option<&ChessDetection>,
+) -> Result<()> {
+    let image = image::open(image_path).context(format!("Failed to load image {}", image_path))?;
+
+    let board = match (board, chess_detector) {
+        (Some(board), _) => board
+            .try_into()
+            .map_err(|_| anyhow!("--board takes x,y,width,height"))?,
+        (None, Some(chess_detector)) => {
+            let detection_level = if args.refined_search {
+                DetectionLevel::Refined
+            } else {
+                DetectionLevel::Basic
+            };
+            let detections = chess_detector
+                .detect(&image, &detection_level)
+                .context("Detection failed")?
+                .context("No chessboard found, pass its position with --board")?;
+            let (best_match, _) = get_best_chessboard_match(&detections)
+                .context("No chessboard found, pass its position with --board")?;
+            [best_match[0], best_match[1], best_match[2], best_match[3]]
+        }
+        (None, None) => {
+            return Err(anyhow!(
+                "No chessboard found, pass its position with --board"
+            ))
+        }
+    };
+
+    let board = match fit_grid(&image, board) {
+        Some(fitted) => fitted,
+        None => {
+            println!("The square grid couldn't be found, using the board as given");
+            board
+        }
+    };
+    let (light_square, dark_square) = square_colours(&image, board)?;
+    let white_pov = white_at_bottom(&image, board, light_square, dark_square)?;
+    let templates = TemplateLibrary::capture(&image, board, white_pov)?;
+
+    // reading the screenshot back checks the templates tell the pieces apart
+    let fen = TemplateRecognizer::new(templates.clone())
+        .recognize(&image, board)?
+        .to_fen(white_pov);
+    if fen != START_PLACEMENT {
+        return Err(anyhow!(
+            "The templates read the screenshot as {}, are the pieces too alike?",
+            fen
+        ));
+    }
+
+    Profile {
+        board,
+        light_square: light_square.0,
+        dark_square: dark_square.0,
+        white_pov,
+        templates,
+    }
+    .save(output)?;
+
+    println!(
+        "Board: {:.2},{:.2},{:.2},{:.2}",
+        board[0], board[1], board[2], board[3]
+    );
+    println!(
+        "Squares: light #{}, dark #{}",
+        hex(light_square),
+        hex(dark_square)
+    );
+    println!(
+        "{} at the bottom",
+        if white_pov { "White" } else { "Black" }
+    );
+    println!("Saved the profile to {}", output);
+    Ok(())
+}
+
+/// Average colours of the light and dark squares of the four empty ranks.
+fn square_colours(image: &DynamicImage, board: [f32; 4]) -> Result<(Rgb<u8>, Rgb<u8>)> {
+    let mut sums = [[0u64; 3]; 2];
+    let mut counts = [0u64; 2];
+    for row in 2..6 {
+        for column in 0..8 {
+            let square = square_centre(image, board, row, column)?;
+            let parity = (row + column) % 2;
+            for pixel in square.pixels() {
+                for (sum, channel) in sums[parity].iter_mut().zip(pixel.0) {
+                    *sum += channel as u64;
+                }
+                counts[parity] += 1;
+            }
+        }
+    }
+
+    let [first, second] =
+        [0, 1].map(|parity| Rgb(sums[parity].map(|sum| (sum / counts[parity].max(1)) as u8)));
+    if luma(first) >= luma(second) {
+        Ok((first, second))
+    } else {
+        Ok((second, first))
+    }
+}
+
+/// Whether the pieces on the two bottom ranks are brighter than those on the two top ranks.
+fn white_at_bottom(
+    image: &DynamicImage,
+    board: [f32; 4],
+    light_square: Rgb<u8>,
+    dark_square: Rgb<u8>,
+) -> Result<bool> {
+    let piece_brightness = |rows: [usize; 2]| -> Result<f32> {
+        let (mut sum, mut count) = (0u64, 0u64);
+        for row in rows {
+            for column in 0..8 {
+                let square = square_centre(image, board, row, column)?;
+                // the top left square is always light
+                let background = luma(if (row + column) % 2 == 0 {
+                    light_square
+                } else {
+                    dark_square
+                });
+                for pixel in square.pixels() {
+                    let brightness = luma(*pixel);
+                    if brightness.abs_diff(background) >= PIECE_CONTRAST {
+                        sum += brightness as u64;
+                        count += 1;
+                    }
+                }
+            }
+        }
+        if count == 0 {
+            return Err(anyhow!(
+                "No pieces found on the board, is the starting position on the screenshot?"
+            ));
+        }
+        Ok(sum as f32 / count as f32)
+    };
+
+    Ok(piece_brightness([6, 7])? > piece_brightness([0, 1])?)
+}
+
+/// The middle half of a square, away from its neighbours.
+fn square_centre(
+    image: &DynamicImage,
+    board: [f32; 4],
+    row: usize,
+    column: usize,
+) -> Result<RgbImage> {
+    let [x, y, width, height] = square_rect(board, row, column);
+    let (x, y) = ((x + width / 4.0).round(), (y + height / 4.0).round());
+    let (width, height) = ((width / 2.0).round() as u32, (height / 2.0).round() as u32);
+    if x < 0.0
+        || y < 0.0
+        || width == 0
+        || height == 0
+        || x as u32 + width > image.width()
+        || y as u32 + height > image.height()
+    {
+        return Err(anyhow!("The board is outside of the image"));
+    }
+    Ok(image
+        .crop_imm(x as u32, y as u32, width, height)
+        .into_rgb8())
+}
+
+fn luma(colour: Rgb<u8>) -> u8 {
+    let [r, g, b] = colour.0.map(|channel| channel as u32);
+    ((r * 299 + g * 587 + b * 114) / 1000) as u8
+}
+
+fn hex(colour: Rgb<u8>) -> String {
+    colour
+        .0
+        .iter()
+        .map(|channel| format!("{:02x}", channel))
+        .collect()
+}
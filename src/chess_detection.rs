@@ -1,9 +1,23 @@
-use imageproc::image::{imageops, imageops::FilterType, DynamicImage, GenericImageView, Rgb, RgbImage};
-use ndarray::{Array, ArrayBase, Axis, Ix4, IxDyn, OwnedRepr};
-use ort::inputs;
+use imageproc::image::DynamicImage;
+use ndarray::{Array4, ArrayBase, Axis, IxDyn, OwnedRepr};
 use ort::session::Session;
-
-pub static PIECE_MAP: [char; 12] = ['p', 'r', 'n', 'b', 'q', 'k', 'P', 'R', 'N', 'B', 'Q', 'K'];
+use ort::value::Tensor;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::grid::fit_grid;
+use crate::model_info::{ModelInfo, OutputFormat, BOARD_CLASS, DEFAULT_LABELS, PIECE_MAP};
+use crate::preprocess::letterbox_into;
+use crate::profile::square_colours_match;
+use crate::recognizer::{square_rect, BoardProbabilities, BoardReading, Recognizer, EMPTY};
+use crate::yolo::decode_raw_head;
+
+// largest movement of the model's board box, relative to its size, for which the grid fitted
+// to it before is reused
+const GRID_CACHE_SHIFT: f32 = 0.01;
+// brightness deviation (0-255) in the middle of a square, beyond twice that of the board's empty
+// squares, above which it doesn't look empty
+const SUSPICIOUS_DEVIATION: f32 = 24.0;
 
 pub enum DetectionLevel {
     Basic,   // Level 1: Detect the board and pieces directly
@@ -11,29 +25,159 @@ pub enum DetectionLevel {
 }
 
 pub struct ChessDetection {
-    session: Session,
+    // None when the board's position is known and the squares are read by the recognizers
+    model: Option<DetectionModel>,
+    // where the board is when there's no model
+    fixed_board: Option<[f32; 4]>,
+    // colours of the light and dark squares of the fixed board, to notice it moved
+    board_colours: Option<([u8; 3], [u8; 3])>,
+    // the fixed board was reported missing already
+    warned_board_moved: AtomicBool,
+    labels: Vec<String>,
     confidence_threshold: f32,
+    iou_threshold: f32,
     refined_padding: f32,
+    // snap the detected board to its square grid, see `fit_grid`
+    fit_grid: bool,
+    // the last board box from the model and the grid fitted to it, fitting takes a while
+    fitted_grid: Mutex<Option<GridFit>>,
+    // read the squares too, see `read_board`
+    recognizers: Vec<Box<dyn Recognizer + Send + Sync>>,
+    use_detections: bool,
+}
+
+struct GridFit {
+    detected: [f32; 4],
+    // None when no grid stood out
+    fitted: Option<[f32; 4]>,
+}
+
+struct DetectionModel {
+    session: Session,
+    info: ModelInfo,
+    // reused for every frame, so it isn't allocated each time
+    input: Mutex<Tensor<f32>>,
 }
 
 impl ChessDetection {
-    pub fn new(session: Session, confidence_threshold: f32, refined_padding: f32) -> Self {
-        Self {
-            session,
+    /// Fails if the model's inputs, outputs or classes aren't supported.
+    pub fn new(
+        session: Session,
+        confidence_threshold: f32,
+        iou_threshold: f32,
+        refined_padding: f32,
+        fit_grid: bool,
+    ) -> anyhow::Result<Self> {
+        let model_info = ModelInfo::from_session(&session)?;
+        let (width, height) = model_info.input_size;
+        let input = Array4::<f32>::zeros((1, 3, height as usize, width as usize));
+        let input = Tensor::from_array(input)?;
+        Ok(Self {
+            labels: model_info.labels.clone(),
+            model: Some(DetectionModel {
+                session,
+                info: model_info,
+                input: Mutex::new(input),
+            }),
+            fixed_board: None,
+            board_colours: None,
+            warned_board_moved: AtomicBool::new(false),
             confidence_threshold,
+            iou_threshold,
             refined_padding,
+            fit_grid,
+            fitted_grid: Mutex::new(None),
+            recognizers: Vec::new(),
+            use_detections: true,
+        })
+    }
+
+    /// Runs no model: the board is always at `board` (x, y, width, height) and its squares have
+    /// to be read by recognizers.
+    pub fn without_model(
+        board: [f32; 4],
+        recognizers: Vec<Box<dyn Recognizer + Send + Sync>>,
+    ) -> Self {
+        Self {
+            model: None,
+            fixed_board: Some(board),
+            board_colours: None,
+            warned_board_moved: AtomicBool::new(false),
+            labels: DEFAULT_LABELS
+                .iter()
+                .map(|label| label.to_string())
+                .collect(),
+            confidence_threshold: 0.0,
+            iou_threshold: 0.0,
+            refined_padding: 0.0,
+            fit_grid: false,
+            fitted_grid: Mutex::new(None),
+            recognizers,
+            use_detections: false,
         }
     }
 
+    /// The board is always at `board` (x, y, width, height), the model only finds the pieces.
+    pub fn with_board(mut self, board: [f32; 4]) -> Self {
+        self.fixed_board = Some(board);
+        self
+    }
+
+    /// The fixed board's squares are `light_square` and `dark_square`. Frames where they aren't are
+    /// searched by the model instead, or have no board without a model.
+    pub fn with_board_colours(mut self, light_square: [u8; 3], dark_square: [u8; 3]) -> Self {
+        self.board_colours = Some((light_square, dark_square));
+        self
+    }
+
+    /// Reads the squares with `recognizers` as well, or instead of the detections unless `use_detections`.
+    pub fn with_recognizers(
+        mut self,
+        use_detections: bool,
+        recognizers: Vec<Box<dyn Recognizer + Send + Sync>>,
+    ) -> Self {
+        self.use_detections = use_detections;
+        self.recognizers = recognizers;
+        self
+    }
+
+    pub fn confidence_threshold(&self) -> f32 {
+        self.confidence_threshold
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.model.as_ref().map(|model| &model.session)
+    }
+
+    pub fn model_info(&self) -> Option<&ModelInfo> {
+        self.model.as_ref().map(|model| &model.info)
+    }
+
+    /// Labels of the detection classes.
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
     fn predict(
         &self,
-        input: ArrayBase<OwnedRepr<f32>, Ix4>,
+        model: &DetectionModel,
+        input: &Tensor<f32>,
     ) -> ort::Result<ArrayBase<OwnedRepr<f32>, IxDyn>> {
-        let outputs = self.session.run(inputs!["images" => input]?)?;
-        let output = outputs["output0"]
-            .try_extract_tensor::<f32>()?
-            .squeeze()
-            .into_owned();
+        let outputs = model
+            .session
+            .run(vec![(model.info.input_name.as_str(), input.view())])?;
+        let output = outputs[model.info.output_name.as_str()].try_extract_tensor::<f32>()?;
+        let mut output = match model.info.output_format {
+            OutputFormat::Detections => output.squeeze().into_owned(),
+            OutputFormat::RawHead => {
+                decode_raw_head(output, self.confidence_threshold, self.iou_threshold).into_dyn()
+            }
+        };
+
+        // the rest of the code works with the canonical class ids
+        for mut row in output.axis_iter_mut(Axis(0)) {
+            row[5] = model.info.canonical_class(row[5]);
+        }
 
         Ok(output)
     }
@@ -70,8 +214,111 @@ impl ChessDetection {
         img: &DynamicImage,
         detection_level: &DetectionLevel,
     ) -> ort::Result<Option<ArrayBase<OwnedRepr<f32>, IxDyn>>> {
-        let (input, x_offset, y_offset, scale) = process_image(img);
-        let mut output = self.predict(input)?;
+        let fixed_board = self
+            .fixed_board
+            .filter(|&board| self.board_in_place(img, board));
+        let Some(model) = &self.model else {
+            let Some(board) = fixed_board else {
+                return Ok(None);
+            };
+            let output = ndarray::arr2(&[[
+                board[0],
+                board[1],
+                board[2],
+                board[3],
+                1.0,
+                BOARD_CLASS as f32,
+            ]]);
+            return Ok(Some(output.into_dyn()));
+        };
+        let Some(board) = fixed_board else {
+            let mut output = self.detect_with_model(model, img, detection_level)?;
+            if let Some(output) = output.as_mut().filter(|_| self.fit_grid) {
+                self.fit_board_grid(img, output);
+            }
+            return Ok(output);
+        };
+
+        // the model only looks for the pieces, around the board when refined
+        let (output, x_offset, y_offset) = match detection_level {
+            DetectionLevel::Refined => {
+                let (cropped_img, new_x, new_y) = crop_with_padding(
+                    img,
+                    board[0] as u32,
+                    board[1] as u32,
+                    board[2] as u32,
+                    board[3] as u32,
+                    self.refined_padding,
+                );
+                let output = self.detect_with_model(model, &cropped_img, &DetectionLevel::Basic)?;
+                (output, new_x as f32, new_y as f32)
+            }
+            DetectionLevel::Basic => (
+                self.detect_with_model(model, img, &DetectionLevel::Basic)?,
+                0.0,
+                0.0,
+            ),
+        };
+        let Some(output) = output else {
+            return Ok(None);
+        };
+
+        let columns = output.shape()[1];
+        let mut rows = Vec::with_capacity(output.len() + columns);
+        for row in output.axis_iter(Axis(0)) {
+            if row[5] != BOARD_CLASS as f32 {
+                rows.push(row[0] + x_offset);
+                rows.push(row[1] + y_offset);
+                rows.extend(row.iter().skip(2));
+            }
+        }
+        rows.extend([
+            board[0],
+            board[1],
+            board[2],
+            board[3],
+            1.0,
+            BOARD_CLASS as f32,
+        ]);
+        rows.resize(rows.len() + columns - 6, 0.0);
+        let output = ndarray::Array2::from_shape_vec((rows.len() / columns, columns), rows)
+            .expect("rows of the model's width");
+        Ok(Some(output.into_dyn()))
+    }
+
+    /// Whether the fixed board still has its colours, warns the first time it doesn't.
+    fn board_in_place(&self, img: &DynamicImage, board: [f32; 4]) -> bool {
+        let Some((light_square, dark_square)) = self.board_colours else {
+            return true;
+        };
+        if square_colours_match(img, board, light_square, dark_square) {
+            return true;
+        }
+        if !self.warned_board_moved.swap(true, Ordering::Relaxed) {
+            eprintln!(
+                "The squares at the profile's board don't have its colours, the board moved or its theme changed. {}",
+                if self.model.is_some() {
+                    "Looking for the board instead."
+                } else {
+                    "Run `chust calibrate` again."
+                }
+            );
+        }
+        false
+    }
+
+    fn detect_with_model(
+        &self,
+        model: &DetectionModel,
+        img: &DynamicImage,
+        detection_level: &DetectionLevel,
+    ) -> ort::Result<Option<ArrayBase<OwnedRepr<f32>, IxDyn>>> {
+        let (mut output, x_offset, y_offset, scale) = {
+            let mut input = model.input.lock().unwrap_or_else(PoisonError::into_inner);
+            let (x_offset, y_offset, scale) =
+                letterbox_into(img, model.info.input_size, input.extract_raw_tensor_mut().1);
+            (self.predict(model, &input)?, x_offset, y_offset, scale)
+        };
 
         if let DetectionLevel::Refined = detection_level {
             let best_detection = match get_best_chessboard_match(&output) {
@@ -90,7 +337,7 @@ impl ChessDetection {
             );
             let (cropped_img, new_x, new_y) =
                 crop_with_padding(img, x, y, w, h, self.refined_padding);
-            output = match self.detect(&cropped_img, &DetectionLevel::Basic)? {
+            output = match self.detect_with_model(model, &cropped_img, &DetectionLevel::Basic)? {
                 Some(out) => out,
                 None => return Ok(None),
             };
@@ -105,67 +352,204 @@ impl ChessDetection {
 
         Ok(Some(output))
     }
+
+    /// Snaps the most confident board to the square grid inside it, so a few pixels of error in
+    /// the model's box don't shift the squares. The box is kept when no grid is found.
+    fn fit_board_grid(&self, img: &DynamicImage, output: &mut ArrayBase<OwnedRepr<f32>, IxDyn>) {
+        let best_board = output
+            .axis_iter_mut(Axis(0))
+            .filter(|row| row[5] == BOARD_CLASS as f32 && row[4] >= self.confidence_threshold)
+            .reduce(|best, row| if row[4] > best[4] { row } else { best });
+        let Some(mut board) = best_board else {
+            return;
+        };
+
+        let detected = [board[0], board[1], board[2], board[3]];
+        if let Some(fitted) = self.cached_grid_fit(img, detected) {
+            for (value, fitted) in board.iter_mut().zip(fitted) {
+                *value = fitted;
+            }
+        }
+    }
+
+    /// The grid fitted to `detected`, reused while the model's box stays where it was.
+    fn cached_grid_fit(&self, img: &DynamicImage, detected: [f32; 4]) -> Option<[f32; 4]> {
+        let mut fitted_grid = self
+            .fitted_grid
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(GridFit {
+            detected: cached,
+            fitted,
+        }) = *fitted_grid
+        {
+            let size = cached[2].max(cached[3]).max(1.0);
+            let shift = cached
+                .iter()
+                .zip(detected)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max);
+            if shift / size <= GRID_CACHE_SHIFT {
+                return fitted;
+            }
+        }
+
+        let fitted = fit_grid(img, detected);
+        *fitted_grid = Some(GridFit { detected, fitted });
+        fitted
+    }
+
+    /// Reads the position on the board from the detections and the square recognizers.
     pub fn output_to_fen(
         &self,
+        image: &DynamicImage,
         output: &ArrayBase<OwnedRepr<f32>, IxDyn>,
-        board_cords: (u32, u32),
-        board_size: (u32, u32),
+        board: [f32; 4],
         white_pov: bool,
-    ) -> String {
+    ) -> anyhow::Result<String> {
+        Ok(self.read_squares(image, output, board, white_pov)?.fen)
+    }
+
+    /// Reads the position like `output_to_fen`, with how confident every square is.
+    pub fn read_squares(
+        &self,
+        image: &DynamicImage,
+        output: &ArrayBase<OwnedRepr<f32>, IxDyn>,
+        board: [f32; 4],
+        white_pov: bool,
+    ) -> anyhow::Result<BoardReading> {
+        let probabilities = self.read_board(image, output, board)?;
+        let empty = std::array::from_fn(|row| {
+            std::array::from_fn(|column| {
+                probabilities.square_reading(row, column, false).class == EMPTY
+            })
+        });
+        Ok(BoardReading::new(
+            &probabilities,
+            &suspicious_squares(image, board, &empty),
+            white_pov,
+        ))
+    }
+
+    /// Averages what the detections (unless recognizers replace them) and the recognizers see
+    /// on the board at `board` (x, y, width, height).
+    pub fn read_board(
+        &self,
+        image: &DynamicImage,
+        output: &ArrayBase<OwnedRepr<f32>, IxDyn>,
+        board: [f32; 4],
+    ) -> anyhow::Result<BoardProbabilities> {
+        let mut readings = Vec::with_capacity(self.recognizers.len() + 1);
+        if self.use_detections || self.recognizers.is_empty() {
+            readings.push(self.square_probabilities(output, board));
+        }
+        for recognizer in &self.recognizers {
+            readings.push(recognizer.recognize(image, board)?);
+        }
+        Ok(BoardProbabilities::combine(&readings))
+    }
+
+    /// Squares with a detected piece have the piece's confidence and the rest goes to empty,
+    /// never more than the piece as it passed the threshold. The others are empty.
+    pub fn square_probabilities(
+        &self,
+        output: &ArrayBase<OwnedRepr<f32>, IxDyn>,
+        board: [f32; 4],
+    ) -> BoardProbabilities {
         let filtered_output = output
             .axis_iter(Axis(0))
-            .filter(|row| row[4] >= self.confidence_threshold && row[5] != 12.0);
-        let cell_size: u32 = ((board_size.0 + board_size.1) / 2) / 8;
-        let half_cell_size = cell_size as f32 / 2.0;
+            .filter(|row| row[4] >= self.confidence_threshold && row[5] != BOARD_CLASS as f32);
+        // unrounded, so the squares follow a grid fitted to a fraction of a pixel
+        let (cell_width, cell_height) = (board[2] / 8.0, board[3] / 8.0);
 
-        let mut board = [[' '; 8]; 8];
+        let mut probabilities = BoardProbabilities::empty();
 
         for detection in filtered_output {
             let (x, y) = (
-                (detection[0]) + half_cell_size,
-                (detection[1]) + half_cell_size,
+                (detection[0]) + cell_width / 2.0,
+                (detection[1]) + cell_height / 2.0,
             );
 
-            let x_location = ((x - board_cords.0 as f32) / cell_size as f32).ceil() as usize;
-            let y_location = ((y - board_cords.1 as f32) / cell_size as f32).ceil() as usize;
+            let x_location = ((x - board[0]) / cell_width).ceil() as usize;
+            let y_location = ((y - board[1]) / cell_height).ceil() as usize;
 
-            if x_location > 8 || x_location < 1 || y_location > 8 || y_location < 1 {
+            if !(1..=8).contains(&x_location) || !(1..=8).contains(&y_location) {
                 continue;
             }
 
-            if let Some(&piece) = PIECE_MAP.get(detection[5] as usize) {
-                if white_pov {
-                    board[y_location - 1][x_location - 1] = piece;
-                } else {
-                    board[8 - y_location][8 - x_location] = piece;
-                }
+            let class = detection[5] as usize;
+            if class < PIECE_MAP.len() {
+                let square = &mut probabilities.squares[y_location - 1][x_location - 1];
+                square[EMPTY] = 0.0;
+                square[class] = square[class].max(detection[4]);
+                let best = square.iter().copied().fold(0.0, f32::max);
+                square[EMPTY] = (1.0 - best).min(best);
             }
         }
 
-        let mut fen = String::with_capacity(64 + 7); // 64 for the board, 7 for the slashes
-        for row in board {
-            let mut empty_count: u8 = 0;
-            for &cell in row.iter() {
-                if cell == ' ' {
-                    empty_count += 1;
-                } else {
-                    if empty_count > 0 {
-                        fen.push((b'0' + empty_count) as char);
-                        empty_count = 0;
-                    }
-                    fen.push(cell);
-                }
-            }
-            if empty_count > 0 {
-                fen.push((b'0' + empty_count) as char);
-            }
-            fen.push('/');
-        }
-        fen.pop(); // Remove the last '/'
-        fen
+        probabilities
     }
 }
 
+/// Squares in `empty` whose middle varies much more than that of the other empty squares of its
+/// colour, so textured boards (e.g. wood) aren't suspicious all over.
+fn suspicious_squares(
+    image: &DynamicImage,
+    board: [f32; 4],
+    empty: &[[bool; 8]; 8],
+) -> [[bool; 8]; 8] {
+    let deviations: [[Option<f32>; 8]; 8] = std::array::from_fn(|row| {
+        std::array::from_fn(|column| middle_deviation(image, square_rect(board, row, column)))
+    });
+
+    // the usual deviation of an empty square, on light and on dark squares
+    let baselines = [0, 1].map(|parity| {
+        let mut empty_deviations: Vec<f32> = (0..64)
+            .map(|index| (index / 8, index % 8))
+            .filter(|&(row, column)| (row + column) % 2 == parity && empty[row][column])
+            .filter_map(|(row, column)| deviations[row][column])
+            .collect();
+        empty_deviations.sort_by(f32::total_cmp);
+        empty_deviations
+            .get(empty_deviations.len() / 2)
+            .copied()
+            .unwrap_or(0.0)
+    });
+
+    std::array::from_fn(|row| {
+        std::array::from_fn(|column| {
+            let baseline = baselines[(row + column) % 2];
+            empty[row][column]
+                && deviations[row][column]
+                    .is_some_and(|deviation| deviation > 2.0 * baseline + SUSPICIOUS_DEVIATION)
+        })
+    })
+}
+
+/// The standard deviation of the brightness in the middle half of `square` (x, y, width, height).
+fn middle_deviation(image: &DynamicImage, square: [f32; 4]) -> Option<f32> {
+    let [x, y, width, height] = square;
+    let (x, y) = (
+        (x + width / 4.0).max(0.0) as u32,
+        (y + height / 4.0).max(0.0) as u32,
+    );
+    let width = ((width / 2.0) as u32).min(image.width().saturating_sub(x));
+    let height = ((height / 2.0) as u32).min(image.height().saturating_sub(y));
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let middle = image.crop_imm(x, y, width, height).into_luma8();
+    let count = middle.len() as f32;
+    let mean = middle.iter().map(|&pixel| pixel as f32).sum::<f32>() / count;
+    let variance = middle
+        .iter()
+        .map(|&pixel| (pixel as f32 - mean).powi(2))
+        .sum::<f32>()
+        / count;
+    Some(variance.sqrt())
+}
+
 pub fn crop_with_padding(
     img: &DynamicImage,
     x: u32,
@@ -202,45 +586,6 @@ pub fn scale_bbox(
     )
 }
 
-pub fn process_image(img: &DynamicImage) -> (ArrayBase<OwnedRepr<f32>, Ix4>, u32, u32, f32) {
-    let (padded_img, x_offset, y_offset, scale) = letterbox_resize(img, 640);
-    let mut input = Array::zeros((1, 3, 640, 640));
-    for (x, y, pixel) in padded_img.enumerate_pixels() {
-        let Rgb([r, g, b]) = *pixel;
-        input[[0, 0, y as usize, x as usize]] = (r as f32) / 255.;
-        input[[0, 1, y as usize, x as usize]] = (g as f32) / 255.;
-        input[[0, 2, y as usize, x as usize]] = (b as f32) / 255.;
-    }
-    (input, x_offset, y_offset, scale)
-}
-
-pub fn letterbox_resize(img: &DynamicImage, target_size: u32) -> (RgbImage, u32, u32, f32) {
-    let (orig_w, orig_h) = img.dimensions();
-
-    // Ensure at least one side is 640 while maintaining aspect ratio
-    let scale = if orig_w > orig_h {
-        target_size as f32 / orig_w as f32
-    } else {
-        target_size as f32 / orig_h as f32
-    };
-
-    let new_w = (orig_w as f32 * scale) as u32;
-    let new_h = (orig_h as f32 * scale) as u32;
-
-    let resized = img
-        .resize_exact(new_w, new_h, FilterType::Lanczos3)
-        .to_rgb8();
-
-    // Calculate padding offsets to center the image
-    let x_offset = (target_size - new_w) / 2;
-    let y_offset = (target_size - new_h) / 2;
-
-    // Create a new black-padded square image
-    let mut padded = RgbImage::new(target_size, target_size);
-    imageops::overlay(&mut padded, &resized, x_offset.into(), y_offset.into());
-    (padded, x_offset, y_offset, scale)
-}
-
 pub fn get_best_chessboard_match(
     model_output: &ArrayBase<OwnedRepr<f32>, IxDyn>,
 ) -> Option<(&[f32], f32)> {
@@ -250,12 +595,84 @@ pub fn get_best_chessboard_match(
         let confidence = row[4];
         let class_id = row[5] as u32;
 
-        if class_id == 12 {
-            if best_detection.is_none() || confidence > best_detection.as_ref().unwrap().1 {
-                best_detection = Some((row.to_slice().unwrap(), confidence));
-            }
+        if class_id == BOARD_CLASS as u32
+            && (best_detection.is_none() || confidence > best_detection.as_ref().unwrap().1)
+        {
+            best_detection = Some((row.to_slice().unwrap(), confidence));
         }
     }
 
     best_detection
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imageproc::image::{Rgb, RgbImage};
+
+    /// A board of 40 pixel squares at (`x`, `y`).
+    fn board_image(x: u32, y: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(400, 400, |px, py| {
+            if px < x || py < y || px >= x + 320 || py >= y + 320 {
+                return Rgb([40, 40, 40]);
+            }
+            let (column, row) = ((px - x) / 40, (py - y) / 40);
+            if (row + column) % 2 == 0 {
+                Rgb([238, 238, 210])
+            } else {
+                Rgb([118, 150, 86])
+            }
+        }))
+    }
+
+    #[test]
+    fn reuses_the_grid_while_the_box_stays() {
+        let detector = ChessDetection::without_model([0.0; 4], Vec::new());
+        let fitted = detector.cached_grid_fit(&board_image(30, 30), [27.0, 33.0, 322.0, 318.0]);
+        assert_eq!(fitted, Some([30.0, 30.0, 320.0, 320.0]));
+
+        // a box within a pixel or two keeps the grid, even though the board isn't there anymore
+        let moved = board_image(60, 60);
+        let fitted = detector.cached_grid_fit(&moved, [28.0, 32.0, 322.0, 318.0]);
+        assert_eq!(fitted, Some([30.0, 30.0, 320.0, 320.0]));
+
+        // a box that moved is fitted again
+        let fitted = detector.cached_grid_fit(&moved, [57.0, 63.0, 322.0, 318.0]);
+        assert_eq!(fitted, Some([60.0, 60.0, 320.0, 320.0]));
+    }
+
+    #[test]
+    fn judges_suspicious_squares_against_the_empty_ones() {
+        // a grainy board: every square varies, none more than the others
+        let mut image = board_image(0, 0).into_rgb8();
+        for (px, _, pixel) in image.enumerate_pixels_mut() {
+            if (px / 2) % 2 == 0 {
+                pixel.0 = pixel.0.map(|channel| channel.saturating_sub(50));
+            }
+        }
+        let board = [0.0, 0.0, 320.0, 320.0];
+        let empty = [[true; 8]; 8];
+        let suspicious = suspicious_squares(&DynamicImage::ImageRgb8(image.clone()), board, &empty);
+        assert!(suspicious.iter().flatten().all(|&square| !square));
+
+        // a piece left on a square read as empty stands out
+        for py in 130..150 {
+            for px in 136..144 {
+                image.put_pixel(px, py, Rgb([0, 0, 0]));
+            }
+        }
+        let image = DynamicImage::ImageRgb8(image);
+        let suspicious = suspicious_squares(&image, board, &empty);
+        let flagged: Vec<(usize, usize)> = (0..64)
+            .map(|index| (index / 8, index % 8))
+            .filter(|&(row, column)| suspicious[row][column])
+            .collect();
+        assert_eq!(flagged, vec![(3, 3)]);
+
+        // only squares read as empty are judged
+        let mut occupied = empty;
+        occupied[3][3] = false;
+        let suspicious = suspicious_squares(&image, board, &occupied);
+        assert!(suspicious.iter().flatten().all(|&square| !square));
+    }
+}
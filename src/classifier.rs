@@ -0,0 +1,206 @@
+// Reads the board square by square with a small ONNX image classifier, e.g. an Ultralytics
+// classification model trained on crops of single squares. This copes better than detection
+// with tiny boards and unusual piece sets.
+//
+// The model takes [squares, 3, height, width] RGB values in 0..1 and returns [squares, classes]
+// probabilities (or logits). Its classes are read from the `names` metadata: the 12 pieces, named
+// like the detection model's, and `empty`. Without `names` they are the pieces in `PIECE_MAP`
+// order followed by empty.
+
+use crate::model_info::{input_size, label_to_class, parse_names, tensor_dimensions, PIECE_MAP};
+use crate::preprocess::letterbox_into;
+use crate::recognizer::{square_rect, BoardProbabilities, Recognizer, CLASS_COUNT, EMPTY};
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::DynamicImage;
+use ndarray::{Array4, Axis};
+use ort::inputs;
+use ort::session::Session;
+
+const DEFAULT_INPUT_SIZE: u32 = 64;
+
+pub struct SquareClassifier {
+    session: Session,
+    input_name: String,
+    output_name: String,
+    input_size: (u32, u32),
+    // squares per run, the model's fixed batch size or all 64 when it takes any number
+    batch_size: usize,
+    // square class of every model class, None for classes that aren't one
+    class_map: Vec<Option<usize>>,
+}
+
+impl SquareClassifier {
+    /// Fails if the model's inputs, outputs or classes aren't supported.
+    pub fn new(session: Session) -> Result<Self> {
+        let [input] = session.inputs.as_slice() else {
+            return Err(anyhow!(
+                "The classifier has {} inputs, expected a single image input",
+                session.inputs.len()
+            ));
+        };
+        let output = session
+            .outputs
+            .first()
+            .context("The classifier has no outputs")?;
+
+        let input_dimensions = tensor_dimensions(&input.input_type)
+            .filter(|dimensions| dimensions.len() == 4 && matches!(dimensions[1], -1 | 3))
+            .context(format!(
+                "The classifier's input `{}` is {}, expected an image tensor of shape [squares, 3, height, width]",
+                input.name, input.input_type
+            ))?;
+        let output_dimensions = tensor_dimensions(&output.output_type)
+            .filter(|dimensions| dimensions.len() == 2)
+            .context(format!(
+                "The classifier's output `{}` is {}, expected probabilities of shape [squares, classes]",
+                output.name, output.output_type
+            ))?;
+
+        let (imgsz, names) = {
+            let metadata = session
+                .metadata()
+                .context("Failed to read the classifier metadata")?;
+            (
+                metadata.custom("imgsz").ok().flatten(),
+                metadata.custom("names").ok().flatten(),
+            )
+        };
+
+        let class_map = match names {
+            Some(names) => map_classes(&parse_names(&names)?)?,
+            None => (0..CLASS_COUNT).map(Some).collect(),
+        };
+        if output_dimensions[1] != -1 && output_dimensions[1] != class_map.len() as i64 {
+            return Err(anyhow!(
+                "The classifier returns {} classes, expected {}",
+                output_dimensions[1],
+                class_map.len()
+            ));
+        }
+
+        let input_size = input_size(input_dimensions, imgsz.as_deref(), DEFAULT_INPUT_SIZE)?;
+        let batch_size = match input_dimensions[0] {
+            size if size > 0 => size as usize,
+            _ => 64,
+        };
+        let (input_name, output_name) = (input.name.clone(), output.name.clone());
+
+        Ok(Self {
+            session,
+            input_name,
+            output_name,
+            input_size,
+            batch_size,
+            class_map,
+        })
+    }
+
+    /// Classifies up to `batch_size` squares in one run, padding the batch with blank squares.
+    fn classify(&self, squares: &[DynamicImage]) -> Result<Vec<[f32; CLASS_COUNT]>> {
+        let (width, height) = self.input_size;
+        let mut input = Array4::<f32>::zeros((
+            self.batch_size.max(squares.len()),
+            3,
+            height as usize,
+            width as usize,
+        ));
+        for (square, mut slot) in squares.iter().zip(input.axis_iter_mut(Axis(0))) {
+            let slot = slot.as_slice_mut().expect("contiguous square in the batch");
+            letterbox_into(square, self.input_size, slot);
+        }
+
+        let outputs = self
+            .session
+            .run(inputs![self.input_name.as_str() => input]?)?;
+        let output = outputs[self.output_name.as_str()].try_extract_tensor::<f32>()?;
+
+        Ok(output
+            .axis_iter(Axis(0))
+            .take(squares.len())
+            .map(|scores| {
+                let scores: Vec<f32> = scores.iter().copied().collect();
+                let probabilities = to_probabilities(&scores);
+
+                let mut square = [0.0; CLASS_COUNT];
+                for (class, probability) in self.class_map.iter().zip(probabilities) {
+                    if let Some(class) = class {
+                        square[*class] += probability;
+                    }
+                }
+                square
+            })
+            .collect())
+    }
+}
+
+impl Recognizer for SquareClassifier {
+    fn recognize(&self, image: &DynamicImage, board: [f32; 4]) -> Result<BoardProbabilities> {
+        let mut squares = Vec::with_capacity(64);
+        for row in 0..8 {
+            for column in 0..8 {
+                let [x, y, width, height] = square_rect(board, row, column);
+                let (x, y) = (x.max(0.0) as u32, y.max(0.0) as u32);
+                let width = (width as u32).min(image.width().saturating_sub(x));
+                let height = (height as u32).min(image.height().saturating_sub(y));
+                if width == 0 || height == 0 {
+                    return Err(anyhow!("The board is outside of the image"));
+                }
+                squares.push(image.crop_imm(x, y, width, height));
+            }
+        }
+
+        let mut classified = Vec::with_capacity(squares.len());
+        for batch in squares.chunks(self.batch_size) {
+            classified.extend(self.classify(batch)?);
+        }
+
+        let mut probabilities = BoardProbabilities::empty();
+        for (index, square) in classified.into_iter().enumerate() {
+            probabilities.squares[index / 8][index % 8] = square;
+        }
+        Ok(probabilities)
+    }
+}
+
+/// Scores already summing to 1 are kept, others go through softmax.
+fn to_probabilities(scores: &[f32]) -> Vec<f32> {
+    let total: f32 = scores.iter().sum();
+    if scores.iter().all(|&score| score >= 0.0) && (total - 1.0).abs() < 0.01 {
+        return scores.to_vec();
+    }
+
+    let max = scores.iter().copied().fold(f32::MIN, f32::max);
+    let exponentials: Vec<f32> = scores.iter().map(|score| (score - max).exp()).collect();
+    let total: f32 = exponentials.iter().sum();
+    exponentials.iter().map(|e| e / total).collect()
+}
+
+/// Maps the classifier's labels to square classes, all pieces and empty must be there.
+fn map_classes(labels: &[String]) -> Result<Vec<Option<usize>>> {
+    let class_map: Vec<Option<usize>> = labels
+        .iter()
+        .map(|label| {
+            if matches!(label.to_lowercase().as_str(), "empty" | "none" | "blank") {
+                Some(EMPTY)
+            } else {
+                label_to_class(label).filter(|&class| class < PIECE_MAP.len())
+            }
+        })
+        .collect();
+
+    let missing: Vec<String> = (0..CLASS_COUNT)
+        .filter(|class| !class_map.contains(&Some(*class)))
+        .map(|class| match PIECE_MAP.get(class) {
+            Some(piece) => piece.to_string(),
+            None => "empty".to_string(),
+        })
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "The classifier has no class for {} (its classes are {})",
+            missing.join(", "),
+            labels.join(", ")
+        ));
+    }
+    Ok(class_map)
+}
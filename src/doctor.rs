@@ -0,0 +1,136 @@
+#[cfg(target_os = "linux")]
+use crate::input_capture::{
+    input_capture_manager::on_wayland,
+    uinput::UINPUT_PATH,
+    wayland::{self, SCREENCOPY_MANAGER, SHM, VIRTUAL_POINTER_MANAGER},
+};
+use anyhow::Result;
+use xcap::Monitor;
+
+/// Prints which capture and input capabilities are available on this machine.
+pub fn doctor() -> Result<()> {
+    println!("Session");
+    for variable in ["XDG_SESSION_TYPE", "WAYLAND_DISPLAY", "DISPLAY"] {
+        println!(
+            "  {}: {}",
+            variable,
+            std::env::var(variable).unwrap_or_else(|_| "(not set)".to_string())
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        println!("\nWayland (wlroots protocols)");
+        match wayland::connect() {
+            Ok((state, _)) => {
+                for global in [SHM, SCREENCOPY_MANAGER, VIRTUAL_POINTER_MANAGER] {
+                    print_capability(global, state.has_global(global));
+                }
+                for (index, output) in state.outputs.iter().enumerate() {
+                    println!(
+                        "  output {}: {} {}x{}",
+                        index,
+                        output.name.as_deref().unwrap_or("(unnamed)"),
+                        output.width.unwrap_or_default(),
+                        output.height.unwrap_or_default()
+                    );
+                }
+            }
+            Err(err) => println!("  unavailable: {}", err),
+        }
+
+        println!("\nDesktop portal");
+        if !cfg!(feature = "pipewire") {
+            println!("  not built in, build with the `pipewire` feature to use it");
+        }
+        match probe_portal() {
+            Ok(interfaces) => {
+                for (interface, version) in interfaces {
+                    match version {
+                        Some(version) => println!("  [ok] {} (version {})", interface, version),
+                        None => print_capability(interface, false),
+                    }
+                }
+            }
+            Err(err) => println!("  unavailable: {}", err),
+        }
+    }
+
+    println!("\nScreen capture (xcap)");
+    match Monitor::all() {
+        Ok(monitors) => {
+            for (index, monitor) in monitors.iter().enumerate() {
+                println!(
+                    "  monitor {}: {} {}x{}",
+                    index,
+                    monitor.name().unwrap_or_default(),
+                    monitor.width().unwrap_or_default(),
+                    monitor.height().unwrap_or_default()
+                );
+            }
+        }
+        Err(err) => println!("  unavailable: {}", err),
+    }
+
+    println!("\nInput (enigo)");
+    print_capability(
+        "enigo",
+        enigo::Enigo::new(&enigo::Settings::default()).is_ok(),
+    );
+
+    #[cfg(target_os = "linux")]
+    {
+        println!("\nInput (uinput)");
+        print_capability(
+            &format!("{} writable", UINPUT_PATH),
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(UINPUT_PATH)
+                .is_ok(),
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    println!(
+        "\nDefault backend: {}",
+        if on_wayland() {
+            if cfg!(feature = "pipewire") {
+                "wlroots protocols, or the desktop portal if they are missing"
+            } else {
+                "wlroots protocols"
+            }
+        } else {
+            "xcap + enigo"
+        }
+    );
+
+    Ok(())
+}
+
+fn print_capability(name: &str, available: bool) {
+    println!("  [{}] {}", if available { "ok" } else { "missing" }, name);
+}
+
+/// Returns the version of each portal interface chust uses, or `None` if it is not available.
+#[cfg(target_os = "linux")]
+fn probe_portal() -> Result<Vec<(&'static str, Option<u32>)>> {
+    use anyhow::Context;
+    use dbus::blocking::{stdintf::org_freedesktop_dbus::Properties, LocalConnection};
+    use std::time::Duration;
+
+    let connection =
+        LocalConnection::new_session().context("Failed to connect to the session bus")?;
+    let proxy = connection.with_proxy(
+        "org.freedesktop.portal.Desktop",
+        "/org/freedesktop/portal/desktop",
+        Duration::from_secs(10),
+    );
+
+    Ok([
+        "org.freedesktop.portal.ScreenCast",
+        "org.freedesktop.portal.RemoteDesktop",
+    ]
+    .into_iter()
+    .map(|interface| (interface, proxy.get::<u32>(interface, "version").ok()))
+    .collect())
+}
@@ -17,17 +17,13 @@ pub fn draw_bounding_box(img: &mut DynamicImage, bbox: (u32, u32, u32, u32), thi
 }
 
 /// Draws a class label at the center of a bounding box.
-pub fn draw_label(img: &mut DynamicImage, bbox: (u32, u32, u32, u32), class_id: u32) {
-    let class_names = [
-        "bP", "bR", "bN", "bB", "bQ", "bK", "wP", "wR", "wN", "wB", "wQ", "wK", "CB"
-    ];
-    let label = class_names.get(class_id as usize).unwrap_or(&"??");
+pub fn draw_label(img: &mut DynamicImage, bbox: (u32, u32, u32, u32), label: &str) {
     let (x, y, width, height) = bbox;
 
     let font_data = include_bytes!("../CaskaydiaCoveNerdFont-Bold.ttf");
     let font = FontArc::try_from_slice(font_data).expect("Failed to load font");
 
-    let text_x = x + (width / 2) - (label.len() as u32 * 15 / 2);
+    let text_x = (x + (width / 2)).saturating_sub(label.len() as u32 * 15 / 2);
     let text_y = y + (height / 2);
 
     draw_text_mut(img, Rgba([255, 0, 0, 255]), text_x as i32, text_y as i32, 30.0, &font, label);
@@ -37,14 +33,15 @@ pub fn draw_label(img: &mut DynamicImage, bbox: (u32, u32, u32, u32), class_id:
 pub fn annotate_detections(
     img: &mut DynamicImage,
     detections: &ArrayBase<OwnedRepr<f32>, IxDyn>,
+    labels: &[String],
     filter: &dyn Fn(&[f32]) -> bool,
 ) {
     for row in detections.axis_iter(ndarray::Axis(0)) {
         let data = row.to_slice().expect("Failed to convert row to slice");
         if filter(data) {
             let (x, y, width, height) = (data[0] as u32, data[1] as u32, data[2] as u32, data[3] as u32);
-            let class_id = data[5] as u32;
-            draw_label(img, (x, y, width, height), class_id);
+            let label = labels.get(data[5] as usize).map_or("??", String::as_str);
+            draw_label(img, (x, y, width, height), label);
             draw_bounding_box(img, (x, y, width, height), 2);
         }
     }
@@ -0,0 +1,79 @@
+// Tells whether the board changed on screen since detection last ran, so identical screenshots
+// don't go through the model again.
+
+use imageproc::image::DynamicImage;
+
+// every square is reduced to SQUARE_CELLS x SQUARE_CELLS average brightnesses
+const SQUARE_CELLS: u32 = 4;
+const SIGNATURE_SIZE: u32 = 8 * SQUARE_CELLS;
+
+pub struct FrameChangeDetector {
+    // largest brightness difference (0-255) of a cell that still counts as unchanged
+    threshold: f32,
+    previous: Option<(Region, Vec<u8>)>,
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl FrameChangeDetector {
+    /// A `threshold` of 0 reports every frame as changed.
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            previous: None,
+        }
+    }
+
+    /// Whether the board region of `image` differs from the frame last passed to `update`.
+    pub fn changed(&self, image: &DynamicImage) -> bool {
+        if self.threshold <= 0.0 {
+            return true;
+        }
+        let Some((region, previous)) = &self.previous else {
+            return true;
+        };
+        let Some(signature) = signature(image, *region) else {
+            return true;
+        };
+
+        signature
+            .iter()
+            .zip(previous)
+            .any(|(&a, &b)| a.abs_diff(b) as f32 > self.threshold)
+    }
+
+    /// Remembers the board region (x, y, width, height) of a frame detection ran on.
+    pub fn update(&mut self, image: &DynamicImage, board: [f32; 4]) {
+        if self.threshold <= 0.0 {
+            return;
+        }
+        let region = Region {
+            x: board[0].max(0.0) as u32,
+            y: board[1].max(0.0) as u32,
+            width: board[2] as u32,
+            height: board[3] as u32,
+        };
+        self.previous = signature(image, region).map(|signature| (region, signature));
+    }
+}
+
+/// Average brightness of a grid of cells over the board, None if the region isn't on the image.
+fn signature(image: &DynamicImage, region: Region) -> Option<Vec<u8>> {
+    let width = region.width.min(image.width().checked_sub(region.x)?);
+    let height = region.height.min(image.height().checked_sub(region.y)?);
+    if width < SIGNATURE_SIZE || height < SIGNATURE_SIZE {
+        return None;
+    }
+
+    let board = image
+        .crop_imm(region.x, region.y, width, height)
+        .thumbnail_exact(SIGNATURE_SIZE, SIGNATURE_SIZE)
+        .into_luma8();
+    Some(board.into_raw())
+}
@@ -0,0 +1,174 @@
+// Finds the exact square grid of a board from a rough bounding box. The lines between squares
+// are where the brightness changes along the whole board, so the grid is the set of 9 evenly
+// spaced lines the brightness changes line up with best. The squares of the grid then have to
+// alternate between a light and a dark colour, or it's something else that lined up.
+
+use crate::recognizer::square_rect;
+use imageproc::image::{DynamicImage, GrayImage};
+
+// how far from the bounding box the board's edges are looked for, as a fraction of its size
+const SEARCH_MARGIN: f32 = 1.0 / 16.0;
+// precision of the fitted lines in pixels
+const STEP: f32 = 0.25;
+// how many times stronger than average the brightness changes on the fitted lines have to be
+const MIN_LINE_STRENGTH: f32 = 2.0;
+// smallest brightness difference (0-255) between the light and the dark squares
+const MIN_SQUARE_CONTRAST: f32 = 8.0;
+// squares that can have another colour than expected, e.g. highlighted after a move
+const MAX_ODD_SQUARES: usize = 8;
+
+/// The board (x, y, width, height) whose squares line up with the brightness changes around
+/// `board` and alternate in colour, None if no such grid stands out.
+pub fn fit_grid(image: &DynamicImage, board: [f32; 4]) -> Option<[f32; 4]> {
+    let [x, y, width, height] = board;
+    let (margin_x, margin_y) = (width * SEARCH_MARGIN, height * SEARCH_MARGIN);
+    let left = (x - margin_x).max(0.0) as u32;
+    let top = (y - margin_y).max(0.0) as u32;
+    let right = ((x + width + margin_x) as u32).min(image.width());
+    let bottom = ((y + height + margin_y) as u32).min(image.height());
+    if right <= left + 16 || bottom <= top + 16 {
+        return None;
+    }
+
+    let region = image
+        .crop_imm(left, top, right - left, bottom - top)
+        .into_luma8();
+    let (columns, rows) = edge_profiles(&region);
+
+    let (fitted_x, fitted_width) = fit_lines(&columns, x - left as f32, width, margin_x)?;
+    let (fitted_y, fitted_height) = fit_lines(&rows, y - top as f32, height, margin_y)?;
+    if !alternates(&region, [fitted_x, fitted_y, fitted_width, fitted_height]) {
+        return None;
+    }
+    Some([
+        left as f32 + fitted_x,
+        top as f32 + fitted_y,
+        fitted_width,
+        fitted_height,
+    ])
+}
+
+/// How much the brightness changes between every column and the one on its left, and between
+/// every row and the one above it.
+fn edge_profiles(region: &GrayImage) -> (Vec<f32>, Vec<f32>) {
+    let (width, height) = region.dimensions();
+    let mut columns = vec![0.0; width as usize];
+    let mut rows = vec![0.0; height as usize];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = region.get_pixel(x, y)[0];
+            if x > 0 {
+                columns[x as usize] += pixel.abs_diff(region.get_pixel(x - 1, y)[0]) as f32;
+            }
+            if y > 0 {
+                rows[y as usize] += pixel.abs_diff(region.get_pixel(x, y - 1)[0]) as f32;
+            }
+        }
+    }
+    (columns, rows)
+}
+
+/// The start and length of the 8 evenly spaced squares along `profile` with the strongest
+/// changes on the 7 lines between them. Only the inner lines count as the board's border
+/// often blends into the page or has coordinates drawn next to it.
+fn fit_lines(profile: &[f32], start: f32, length: f32, margin: f32) -> Option<(f32, f32)> {
+    let average = profile.iter().sum::<f32>() / profile.len() as f32;
+    if average <= 0.0 {
+        return None;
+    }
+    // interpolated between pixels, so the lines can fall between them
+    let strength = |position: f32| -> f32 {
+        if position < 0.0 {
+            return 0.0;
+        }
+        let (index, fraction) = (position as usize, position.fract());
+        let at = |index: usize| profile.get(index).copied().unwrap_or(0.0);
+        at(index) * (1.0 - fraction) + at(index + 1) * fraction
+    };
+
+    let mut best: Option<(f32, f32, f32)> = None;
+    // on multiples of the step, so whole pixels are tried
+    let mut size = ((length - margin) / STEP).floor() * STEP / 8.0;
+    while size <= (length + margin) / 8.0 {
+        let mut origin = ((start - margin) / STEP).floor() * STEP;
+        while origin <= start + margin {
+            let score: f32 = (1..8)
+                .map(|line| strength(origin + line as f32 * size))
+                .sum();
+            if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                best = Some((score, origin, size));
+            }
+            origin += STEP;
+        }
+        size += STEP / 8.0;
+    }
+
+    let (score, origin, size) = best?;
+    if score / 7.0 < average * MIN_LINE_STRENGTH {
+        return None;
+    }
+    Some((origin, size * 8.0))
+}
+
+/// Whether the squares of the board at `board` in `region` are light and dark in turn. Pieces
+/// rarely reach into the corners of their square, so only those are looked at.
+fn alternates(region: &GrayImage, board: [f32; 4]) -> bool {
+    let mut brightness = [[0.0; 8]; 8];
+    for (row, squares) in brightness.iter_mut().enumerate() {
+        for (column, square) in squares.iter_mut().enumerate() {
+            let [x, y, width, height] = square_rect(board, row, column);
+            let (patch_width, patch_height) = (width / 6.0, height / 6.0);
+            let mut corners = [
+                (x + patch_width / 2.0, y + patch_height / 2.0),
+                (x + width - patch_width * 1.5, y + patch_height / 2.0),
+                (x + patch_width / 2.0, y + height - patch_height * 1.5),
+                (
+                    x + width - patch_width * 1.5,
+                    y + height - patch_height * 1.5,
+                ),
+            ]
+            .map(|(x, y)| patch_brightness(region, x, y, patch_width, patch_height));
+            // a coordinate or a piece can cover one corner, and the median ignores it
+            corners.sort_by(f32::total_cmp);
+            *square = (corners[1] + corners[2]) / 2.0;
+        }
+    }
+
+    let mut sums = [0.0; 2];
+    for (row, squares) in brightness.iter().enumerate() {
+        for (column, square) in squares.iter().enumerate() {
+            sums[(row + column) % 2] += square;
+        }
+    }
+    let means = sums.map(|sum| sum / 32.0);
+    if (means[0] - means[1]).abs() < MIN_SQUARE_CONTRAST {
+        return false;
+    }
+
+    let mut odd_squares = 0;
+    for (row, squares) in brightness.iter().enumerate() {
+        for (column, square) in squares.iter().enumerate() {
+            let parity = (row + column) % 2;
+            if (square - means[parity]).abs() > (square - means[1 - parity]).abs() {
+                odd_squares += 1;
+            }
+        }
+    }
+    odd_squares <= MAX_ODD_SQUARES
+}
+
+/// Average brightness of a patch, 0 where it's outside of the region.
+fn patch_brightness(region: &GrayImage, x: f32, y: f32, width: f32, height: f32) -> f32 {
+    let (left, top) = (x.max(0.0) as u32, y.max(0.0) as u32);
+    let right = ((x + width).ceil() as u32).min(region.width());
+    let bottom = ((y + height).ceil() as u32).min(region.height());
+
+    let (mut sum, mut count) = (0u32, 0u32);
+    for y in top..bottom {
+        for x in left..right {
+            sum += region.get_pixel(x, y)[0] as u32;
+            count += 1;
+        }
+    }
+    sum as f32 / count.max(1) as f32
+}
@@ -0,0 +1,215 @@
+// Runs the user's custom screenshot/click/drag commands.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How custom commands are started.
+#[derive(Debug, Clone)]
+pub struct CommandRunner {
+    /// Pass the command to the system shell instead of splitting it into words ourselves.
+    pub shell: bool,
+    /// Kill the command if it runs for longer than this.
+    pub timeout: Duration,
+}
+
+impl CommandRunner {
+    pub fn new(shell: bool, timeout_secs: f32) -> Self {
+        Self {
+            shell,
+            timeout: Duration::from_secs_f32(timeout_secs),
+        }
+    }
+
+    /// Builds the command for `template` with its `{name}` placeholders filled with `values`.
+    ///
+    /// Without the shell the template is split into words first (with shell-like quoting), so a
+    /// substituted value always stays a single argument. With the shell the values are quoted.
+    pub fn command(&self, template: &str, values: &[(&str, String)]) -> Result<Command> {
+        let command = if self.shell {
+            let line = substitute(template, values, |name, value| {
+                quote(value)
+                    .ok_or_else(|| anyhow!("Value `{}` for {{{}}} can't be quoted", value, name))
+            })?;
+            shell_command(line)
+        } else {
+            let words = shlex::split(template)
+                .context(format!("Unbalanced quotes in command `{}`", template))?;
+            let mut words = words
+                .iter()
+                .map(|word| substitute(word, values, |_, value| Ok(value.to_string())))
+                .collect::<Result<Vec<_>>>()?
+                .into_iter();
+
+            let mut command = Command::new(words.next().context("No command provided")?);
+            command.args(words);
+            command
+        };
+        Ok(command)
+    }
+
+    /// Runs `template` (see [`CommandRunner::command`]) and returns its stdout.
+    pub fn run(&self, template: &str, values: &[(&str, String)]) -> Result<Vec<u8>> {
+        let mut process = self
+            .command(template, values)?
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!("Failed to execute {}", template))?;
+
+        // read on separate threads so a full pipe can't block the command while we wait for it
+        let stdout = read_to_end(process.stdout.take());
+        let stderr = read_to_end(process.stderr.take());
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = process.try_wait()? {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                process.kill().ok();
+                process.wait().ok();
+                bail!(
+                    "Command `{}` timed out after {:.1}s",
+                    template,
+                    self.timeout.as_secs_f32()
+                );
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+
+        let stdout = stdout.join().unwrap_or_default();
+        let stderr = stderr.join().unwrap_or_default();
+
+        if !status.success() {
+            bail!(
+                "Command `{}` failed ({}): {}",
+                template,
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            );
+        }
+
+        Ok(stdout)
+    }
+}
+
+/// Replaces every `{name}` of `values` in `text` with `fill(name, value)`, in a single pass so
+/// that placeholders within the values are left as they are. Other braces are kept.
+fn substitute(
+    text: &str,
+    values: &[(&str, String)],
+    mut fill: impl FnMut(&str, &str) -> Result<String>,
+) -> Result<String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(name, value)| (end, name, value))
+        });
+        match value {
+            Some((end, name, value)) => {
+                result.push_str(&fill(name, value)?);
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Quotes `value` for the shell that runs the commands.
+#[cfg(not(target_os = "windows"))]
+fn quote(value: &str) -> Option<String> {
+    shlex::try_quote(value)
+        .ok()
+        .map(|quoted| quoted.into_owned())
+}
+
+/// Quotes `value` for `cmd`, which expands variables even inside quotes and can't escape a quote
+/// within them, so values with those characters are refused.
+#[cfg(target_os = "windows")]
+fn quote(value: &str) -> Option<String> {
+    if value.contains(['"', '%', '!', '\n', '\r']) {
+        return None;
+    }
+    Some(format!("\"{}\"", value))
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell_command(line: String) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(line);
+    command
+}
+
+#[cfg(target_os = "windows")]
+fn shell_command(line: String) -> Command {
+    use std::os::windows::process::CommandExt;
+
+    // with /S cmd only strips the outer quotes and runs the line as written
+    let mut command = Command::new("cmd");
+    command.args(["/S", "/C"]).raw_arg(format!("\"{}\"", line));
+    command
+}
+
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut buffer).ok();
+        }
+        buffer
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Vec<(&'static str, String)> {
+        vec![("x", "{y}".to_string()), ("y", "20".to_string())]
+    }
+
+    #[test]
+    fn substitutes_each_placeholder_once() {
+        let filled = substitute("{x},{y} {x}{y} {z} {", &values(), |_, value| {
+            Ok(value.to_string())
+        });
+        assert_eq!(filled.unwrap(), "{y},20 {y}20 {z} {");
+    }
+
+    #[test]
+    fn keeps_substituted_values_as_single_arguments() {
+        let runner = CommandRunner::new(false, 1.0);
+        let command = runner
+            .command("click --at '{x} {y}' {y}", &values())
+            .unwrap();
+        assert_eq!(command.get_program(), "click");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--at", "{y} 20", "20"]
+        );
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn quotes_values_for_the_shell() {
+        let runner = CommandRunner::new(true, 1.0);
+        let values = vec![("file", "a b; {file}".to_string())];
+        let output = runner.run("printf %s {file}", &values).unwrap();
+        assert_eq!(output, b"a b; {file}");
+    }
+}
@@ -0,0 +1,197 @@
+// Talks to one long-lived helper process instead of spawning a command per screenshot/click.
+//
+// Protocol: chust writes one request per line to the helper's stdin:
+//   screenshot
+//   click <x> <y> [square]
+//   press <x> <y> [square]
+//   move <x> <y>
+//   release <x> <y> [square]
+//   quit
+// and the helper answers every request (except quit) on stdout with one line:
+//   ok                 the request succeeded
+//   ok <size>          followed by <size> bytes of payload (the encoded image for `screenshot`)
+//   error <message>    the request failed
+// Anything the helper prints to stderr is passed through. A helper that doesn't answer in time
+// is restarted.
+
+use crate::input_capture::command::CommandRunner;
+use crate::input_capture::InputCaptureTrait;
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{self, DynamicImage};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+type Response = std::result::Result<Vec<u8>, String>;
+
+pub struct HelperInputCapture {
+    helper_command: String,
+    command_runner: CommandRunner,
+
+    process: Child,
+    stdin: ChildStdin,
+    responses: Receiver<Response>,
+
+    target_square: Option<String>,
+}
+
+impl HelperInputCapture {
+    pub fn new(helper_command: &str, command_runner: &CommandRunner) -> Result<Self> {
+        let (process, stdin, responses) = start_helper(helper_command, command_runner)?;
+
+        Ok(Self {
+            helper_command: helper_command.to_string(),
+            command_runner: command_runner.clone(),
+            process,
+            stdin,
+            responses,
+            target_square: None,
+        })
+    }
+
+    fn request(&mut self, request: &str) -> Result<Vec<u8>> {
+        writeln!(self.stdin, "{}", request).context("Failed to write request to the helper")?;
+        self.stdin.flush().context("Failed to flush stdin")?;
+
+        match self.responses.recv_timeout(self.command_runner.timeout) {
+            Ok(Ok(payload)) => Ok(payload),
+            Ok(Err(message)) => Err(anyhow!("Helper failed `{}`: {}", request, message)),
+            Err(RecvTimeoutError::Timeout) => {
+                // its late answer would be taken for the next request's, so start over
+                self.restart()?;
+                Err(anyhow!(
+                    "Helper did not answer `{}` within {:.1}s and was restarted",
+                    request,
+                    self.command_runner.timeout.as_secs_f32()
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Helper exited")),
+        }
+    }
+
+    /// Kills the helper and starts a new one.
+    fn restart(&mut self) -> Result<()> {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+
+        let (process, stdin, responses) = start_helper(&self.helper_command, &self.command_runner)?;
+        self.process = process;
+        self.stdin = stdin;
+        self.responses = responses;
+        Ok(())
+    }
+
+    fn pointer_request(&mut self, action: &str, x: u32, y: u32) -> Result<()> {
+        let request = match &self.target_square {
+            Some(square) => format!("{} {} {} {}", action, x, y, square),
+            None => format!("{} {} {}", action, x, y),
+        };
+        self.request(&request)?;
+        Ok(())
+    }
+}
+
+fn start_helper(
+    helper_command: &str,
+    command_runner: &CommandRunner,
+) -> Result<(Child, ChildStdin, Receiver<Response>)> {
+    let mut process = command_runner
+        .command(helper_command, &[])?
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context(format!("Failed to start helper {}", helper_command))?;
+
+    let stdin = process.stdin.take().context("Failed to open stdin")?;
+    let stdout = BufReader::new(process.stdout.take().context("Failed to open stdout")?);
+
+    // responses are read on a thread so a stuck helper runs into the timeout
+    let (sender, responses) = mpsc::channel();
+    thread::spawn(move || read_responses(stdout, sender));
+
+    Ok((process, stdin, responses))
+}
+
+fn read_responses(mut stdout: BufReader<ChildStdout>, sender: mpsc::Sender<Response>) {
+    loop {
+        let mut line = String::new();
+        match stdout.read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+
+        let line = line.trim_end();
+        let response = if line == "ok" {
+            Ok(Vec::new())
+        } else if let Some(size) = line.strip_prefix("ok ") {
+            let Ok(size) = size.trim().parse::<usize>() else {
+                let _ = sender.send(Err(format!("invalid payload size in `{}`", line)));
+                return;
+            };
+            let mut payload = vec![0u8; size];
+            if stdout.read_exact(&mut payload).is_err() {
+                return;
+            }
+            Ok(payload)
+        } else if line == "error" {
+            Err(String::new())
+        } else if let Some(message) = line.strip_prefix("error ") {
+            Err(message.trim().to_string())
+        } else {
+            Err(format!("unexpected response `{}`", line))
+        };
+
+        if sender.send(response).is_err() {
+            return;
+        }
+    }
+}
+
+impl InputCaptureTrait for HelperInputCapture {
+    fn screenshot(&mut self) -> Result<DynamicImage> {
+        let payload = self.request("screenshot")?;
+        image::load_from_memory(&payload).context("Failed to load image from helper")
+    }
+
+    fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_request("click", x, y)
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_request("press", x, y)
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.request(&format!("move {} {}", x, y))?;
+        Ok(())
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_request("release", x, y)
+    }
+
+    fn set_target_square(&mut self, square: Option<&str>) {
+        self.target_square = square.map(str::to_string);
+    }
+}
+
+impl Drop for HelperInputCapture {
+    fn drop(&mut self) {
+        let _ = writeln!(self.stdin, "quit");
+        let _ = self.stdin.flush();
+
+        // don't hang on exit if the helper ignores quit
+        let deadline = std::time::Instant::now() + self.command_runner.timeout;
+        while let Ok(None) = self.process.try_wait() {
+            if std::time::Instant::now() > deadline {
+                let _ = self.process.kill();
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.process.wait();
+    }
+}
@@ -1,23 +1,31 @@
+#[cfg(all(target_os = "linux", feature = "pipewire"))]
+use crate::input_capture::portal::InputCapturePortal;
 #[cfg(target_os = "linux")]
-use crate::input_capture::wayland::State;
+use crate::input_capture::uinput::{InputCaptureUinput, PointerCalibration};
+#[cfg(target_os = "linux")]
+use crate::input_capture::wayland::{self, State, SCREENCOPY_MANAGER, VIRTUAL_POINTER_MANAGER};
 #[cfg(target_os = "linux")]
 use std::time::{SystemTime, UNIX_EPOCH};
 #[cfg(target_os = "linux")]
 use wayland_client::protocol::wl_pointer::ButtonState;
 #[cfg(target_os = "linux")]
-use wayland_client::{Connection, EventQueue, QueueHandle};
+use wayland_client::protocol::wl_shm;
+#[cfg(target_os = "linux")]
+use wayland_client::{EventQueue, QueueHandle};
 
+use crate::input_capture::command::CommandRunner;
+use crate::input_capture::helper::HelperInputCapture;
+use crate::input_capture::replay::ReplayInputCapture;
 use crate::input_capture::InputCaptureTrait;
 use anyhow::{Context, Result};
 use enigo::Button as EnigoButton;
 use enigo::Coordinate::Abs;
-use enigo::Direction::{Click, Release};
+use enigo::Direction::{Click, Press, Release};
 use enigo::Mouse;
 use imageproc::image;
 use imageproc::image::DynamicImage;
 use imageproc::image::{ImageBuffer, Rgb};
 
-use std::process::{Command, Stdio};
 use xcap::Monitor;
 
 const LEFT_BUTTON: u32 = 0x110;
@@ -51,6 +59,23 @@ impl InputCaptureTrait for InputCapture {
         self.enigo.button(EnigoButton::Left, Release)?;
         Ok(())
     }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.enigo.move_mouse(x as i32, y as i32, Abs)?;
+        self.enigo.button(EnigoButton::Left, Press)?;
+        Ok(())
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.enigo.move_mouse(x as i32, y as i32, Abs)?;
+        Ok(())
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.enigo.move_mouse(x as i32, y as i32, Abs)?;
+        self.enigo.button(EnigoButton::Left, Release)?;
+        Ok(())
+    }
 }
 
 // For linux (wayland)
@@ -65,13 +90,16 @@ pub struct InputCaptureWayland {
 #[cfg(target_os = "linux")]
 impl InputCaptureWayland {
     pub fn new(output_index: usize) -> Result<Self> {
-        let connection = Connection::connect_to_env()?;
-        let mut event_queue = connection.new_event_queue::<State>();
+        let (mut state, mut event_queue) = wayland::connect()?;
         let qhandle = event_queue.handle();
-        let mut state = State::new();
-        let display = connection.display();
-        display.get_registry(&qhandle, ());
-        event_queue.roundtrip(&mut state)?;
+
+        // probe everything we need up front, so we can fall back to another backend
+        for global in [SCREENCOPY_MANAGER, VIRTUAL_POINTER_MANAGER, wayland::SHM] {
+            if !state.has_global(global) {
+                return Err(wayland::WaylandError::MissingGlobal(global).into());
+            }
+        }
+
         state.create_new_vp(output_index, &qhandle, &mut event_queue)?;
 
         Ok(Self {
@@ -93,22 +121,56 @@ impl InputCaptureTrait for InputCaptureWayland {
         )?;
 
         let output = &self.state.outputs[self.output_index];
-        let buffer = output.readable_buffer.unwrap();
-        let width = output.width.unwrap() as u32;
-        let height = output.height.unwrap() as u32;
-
-        Ok(from_xbgr8888(width, height, buffer))
+        let buffer = output
+            .readable_buffer
+            .context("The compositor did not provide a buffer")?;
+        let format = output
+            .buffer_format
+            .context("The compositor did not provide a buffer format")?;
+
+        to_rgb_image(
+            output.buffer_width,
+            output.buffer_height,
+            output.buffer_stride,
+            format,
+            buffer,
+        )
     }
 
     fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(ButtonState::Pressed))?;
+        self.pointer_event(x, y, Some(ButtonState::Released))
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(ButtonState::Pressed))
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, None)
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(ButtonState::Released))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl InputCaptureWayland {
+    /// Moves the virtual pointer to (x, y) and optionally presses or releases the left button.
+    fn pointer_event(&mut self, x: u32, y: u32, button: Option<ButtonState>) -> Result<()> {
         let output = &self.state.outputs[self.output_index];
-        let w = output.width.unwrap();
-        let h = output.height.unwrap();
-        let vp = output.vp.as_ref().unwrap();
+        let w = output.width.context("The output size is unknown")?;
+        let h = output.height.context("The output size is unknown")?;
+        let vp = output
+            .vp
+            .as_ref()
+            .context("The virtual pointer was not created")?;
 
         vp.motion_absolute(time(), x, y, w as u32, h as u32);
-        vp.button(time(), LEFT_BUTTON, ButtonState::Pressed);
-        vp.button(time(), LEFT_BUTTON, ButtonState::Released);
+        if let Some(button) = button {
+            vp.button(time(), LEFT_BUTTON, button);
+        }
         self.event_queue.roundtrip(&mut self.state)?;
 
         Ok(())
@@ -120,6 +182,14 @@ pub struct CustomInputCapture {
 
     custom_screenshot_command: Option<String>,
     custom_click_command: Option<String>,
+    custom_drag_command: Option<String>,
+    command_runner: CommandRunner,
+    output_index: usize,
+
+    // square the next pointer action targets, for the {square} placeholder
+    target_square: Option<String>,
+    // where the current drag started, the drag command runs once the button is released
+    drag_start: Option<(u32, u32, Option<String>)>,
 }
 
 // for when the user wishes to use a custom screenshot/click command
@@ -128,8 +198,14 @@ impl CustomInputCapture {
         input_capture: Option<Box<dyn InputCaptureTrait>>,
         custom_screenshot_command: Option<String>,
         custom_click_command: Option<String>,
+        custom_drag_command: Option<String>,
+        command_runner: CommandRunner,
+        output_index: usize,
     ) -> Result<Self> {
-        if custom_click_command.is_none() && custom_screenshot_command.is_none() {
+        if custom_click_command.is_none()
+            && custom_screenshot_command.is_none()
+            && custom_drag_command.is_none()
+        {
             return Err(anyhow::anyhow!(
                 "Either input_capture or custom_screenshot_command must be provided"
             ));
@@ -139,83 +215,130 @@ impl CustomInputCapture {
             input_capture,
             custom_screenshot_command,
             custom_click_command,
+            custom_drag_command,
+            command_runner,
+            output_index,
+            target_square: None,
+            drag_start: None,
         })
     }
 
-    fn execute_command(command: &str, return_output: bool) -> Result<Option<Vec<u8>>> {
-        let mut parts = command.split_whitespace();
-        let cmd = parts.next().context("No command provided")?;
-        let args: Vec<&str> = parts.collect(); // Remaining parts are arguments
+    fn fallback(&mut self) -> Result<&mut Box<dyn InputCaptureTrait>> {
+        self.input_capture
+            .as_mut()
+            .context("No input capture provided")
+    }
 
-        let mut process = Command::new(cmd)
-            .args(args)
-            .stdout(if return_output {
-                Stdio::piped()
-            } else {
-                Stdio::null()
-            })
-            .stderr(Stdio::null()) // Suppress stderr
-            .spawn()
-            .context(format!("Failed to execute {}", command))?; // Handle errors gracefully
-
-        if return_output {
-            let output = process.wait_with_output()?;
-            Ok(Some(output.stdout))
-        } else {
-            process.wait()?;
-            Ok(None)
-        }
+    /// Placeholders available to every command.
+    fn common_values(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("output", self.output_index.to_string()),
+            ("button", "left".to_string()),
+            ("square", self.target_square.clone().unwrap_or_default()),
+        ]
     }
 }
 
 impl InputCaptureTrait for CustomInputCapture {
     fn screenshot(&mut self) -> Result<DynamicImage> {
         if let Some(ss_command) = &self.custom_screenshot_command {
-            let output = Self::execute_command(ss_command, true)?
-                .context("No output from custom screenshot command")?;
+            let output = self.command_runner.run(ss_command, &self.common_values())?;
+            if output.is_empty() {
+                return Err(anyhow::anyhow!("No output from custom screenshot command"));
+            }
             Ok(image::load_from_memory(&output)
                 .context("Failed to load image from custom screenshot command output")?)
         } else {
-            self.input_capture
-                .as_mut()
-                .context("No input capture provided")?
-                .screenshot()
+            self.fallback()?.screenshot()
         }
     }
 
     fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
         if let Some(click_command) = &self.custom_click_command {
-            Self::execute_command(
-                &click_command
-                    .replace("{x}", &x.to_string())
-                    .replace("{y}", &y.to_string()),
-                false,
-            )?;
+            let mut values = self.common_values();
+            values.push(("x", x.to_string()));
+            values.push(("y", y.to_string()));
+            self.command_runner.run(click_command, &values)?;
+            Ok(())
         } else {
-            self.input_capture
-                .as_mut()
-                .context("No input capture provided")?
-                .click_at(x, y)?;
+            self.fallback()?.click_at(x, y)
+        }
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        if self.custom_drag_command.is_some() {
+            self.drag_start = Some((x, y, self.target_square.clone()));
+            Ok(())
+        } else {
+            self.fallback()?.press_at(x, y)
+        }
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        if self.custom_drag_command.is_some() {
+            // the drag command performs the whole motion on release
+            Ok(())
+        } else {
+            self.fallback()?.move_to(x, y)
+        }
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        if let Some(drag_command) = &self.custom_drag_command {
+            let (from_x, from_y, from_square) = self
+                .drag_start
+                .take()
+                .context("Release without a preceding press")?;
+
+            let mut values = self.common_values();
+            values.push(("from_x", from_x.to_string()));
+            values.push(("from_y", from_y.to_string()));
+            values.push(("from_square", from_square.unwrap_or_default()));
+            values.push(("to_x", x.to_string()));
+            values.push(("to_y", y.to_string()));
+            self.command_runner.run(drag_command, &values)?;
+            Ok(())
+        } else {
+            self.fallback()?
+                .release_at(x, y)
+                .context("Drag moves need --drag-command or a native input backend")
+        }
+    }
+
+    fn set_target_square(&mut self, square: Option<&str>) {
+        self.target_square = square.map(str::to_string);
+        if let Some(input_capture) = self.input_capture.as_mut() {
+            input_capture.set_target_square(square);
         }
-        Ok(())
     }
 }
 
 #[cfg(target_os = "linux")]
-fn from_xbgr8888(width: u32, height: u32, data: &'static [u8]) -> DynamicImage {
-    let new_len = (data.len() / 4) * 3;
-    let mut rgb_data = Vec::with_capacity(new_len);
-    for chunk in data.chunks_exact(4) {
-        let r = chunk[0];
-        let g = chunk[1];
-        let b = chunk[2];
-        rgb_data.extend_from_slice(&[r, g, b]);
+fn to_rgb_image(
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: wl_shm::Format,
+    data: &'static [u8],
+) -> Result<DynamicImage> {
+    // (A|X)RGB8888 is stored as B, G, R, X in memory, (A|X)BGR8888 as R, G, B, X
+    let bgr = matches!(format, wl_shm::Format::Xrgb8888 | wl_shm::Format::Argb8888);
+
+    let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
+    for row in data.chunks_exact(stride as usize).take(height as usize) {
+        for chunk in row[..(width * 4) as usize].chunks_exact(4) {
+            if bgr {
+                rgb_data.extend_from_slice(&[chunk[2], chunk[1], chunk[0]]);
+            } else {
+                rgb_data.extend_from_slice(&[chunk[0], chunk[1], chunk[2]]);
+            }
+        }
     }
 
-    let img_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> =
-        ImageBuffer::from_raw(width, height, rgb_data).expect("Failed to create ImageBuffer");
+    let img_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_raw(width, height, rgb_data)
+        .context("The frame is smaller than its reported size")?;
 
-    DynamicImage::ImageRgb8(img_buffer)
+    Ok(DynamicImage::ImageRgb8(img_buffer))
 }
 
 #[cfg(target_os = "linux")]
@@ -227,26 +350,122 @@ fn time() -> u32 {
 }
 
 #[cfg(target_os = "linux")]
-fn on_wayland() -> bool {
+pub fn on_wayland() -> bool {
     std::env::var("WAYLAND_DISPLAY").is_ok()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_input_capture(
     output_index: usize,
     custom_click_command: Option<String>,
     custom_screenshot_command: Option<String>,
+    custom_drag_command: Option<String>,
+    helper_command: Option<String>,
+    replay: Option<String>,
+    replay_interval: Option<f32>,
+    click_log: &str,
+    uinput: bool,
+    uinput_calibration: Option<String>,
+    command_runner: CommandRunner,
 ) -> Result<Box<dyn InputCaptureTrait>> {
-    if custom_click_command.is_some() && custom_screenshot_command.is_some() {
-        return Ok(Box::new(CustomInputCapture::new(
+    if let Some(replay) = replay {
+        return Ok(Box::new(ReplayInputCapture::new(
+            &replay,
+            replay_interval,
+            click_log,
+        )?));
+    }
+
+    let input_capture: Box<dyn InputCaptureTrait> = if let Some(helper_command) = helper_command {
+        Box::new(HelperInputCapture::new(&helper_command, &command_runner)?)
+    // the uinput pointer replaces the click backend, so a screenshot command is enough
+    } else if custom_screenshot_command.is_some() && (custom_click_command.is_some() || uinput) {
+        Box::new(CustomInputCapture::new(
             None,
             custom_screenshot_command,
             custom_click_command,
-        )?));
+            custom_drag_command,
+            command_runner,
+            output_index,
+        )?)
+    } else {
+        create_native_input_capture(
+            output_index,
+            custom_click_command,
+            custom_screenshot_command,
+            custom_drag_command,
+            command_runner,
+        )?
+    };
+
+    if !uinput {
+        return Ok(input_capture);
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let calibration = match uinput_calibration {
+            Some(spec) => PointerCalibration::parse(&spec)?,
+            None => PointerCalibration::from_monitors(output_index)?,
+        };
+        Ok(Box::new(InputCaptureUinput::new(
+            input_capture,
+            calibration,
+        )?))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = uinput_calibration;
+        Err(anyhow::anyhow!("--uinput is only supported on Linux"))
     }
+}
 
+#[cfg(target_os = "linux")]
+fn is_missing_global(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<wayland::WaylandError>(),
+        Some(wayland::WaylandError::MissingGlobal(_))
+    )
+}
+
+/// The desktop portal backend, used when the compositor is missing the wlroots protocols.
+#[cfg(target_os = "linux")]
+fn portal_input_capture(
+    output_index: usize,
+    err: anyhow::Error,
+) -> Result<Box<dyn InputCaptureTrait>> {
+    #[cfg(feature = "pipewire")]
+    {
+        eprintln!("{}. Falling back to the desktop portal.", err);
+        Ok(Box::new(InputCapturePortal::new(output_index)?))
+    }
+
+    #[cfg(not(feature = "pipewire"))]
+    {
+        let _ = output_index;
+        Err(err.context(
+            "The desktop portal backend needs Chust to be built with the `pipewire` feature \
+             (cargo build --release --features pipewire)",
+        ))
+    }
+}
+
+fn create_native_input_capture(
+    output_index: usize,
+    custom_click_command: Option<String>,
+    custom_screenshot_command: Option<String>,
+    custom_drag_command: Option<String>,
+    command_runner: CommandRunner,
+) -> Result<Box<dyn InputCaptureTrait>> {
     #[cfg(target_os = "linux")]
     let input_capture: Box<dyn InputCaptureTrait> = if on_wayland() {
-        Box::new(InputCaptureWayland::new(output_index)?)
+        match InputCaptureWayland::new(output_index) {
+            Ok(input_capture) => Box::new(input_capture),
+            // only a compositor without the wlroots protocols needs the portal
+            Err(err) if is_missing_global(&err) => portal_input_capture(output_index, err)?,
+            Err(err) => return Err(err),
+        }
     } else {
         Box::new(InputCapture::new(output_index)?)
     };
@@ -254,11 +473,17 @@ pub fn create_input_capture(
     #[cfg(not(target_os = "linux"))]
     let input_capture: Box<dyn InputCaptureTrait> = Box::new(InputCapture::new(output_index)?);
 
-    if custom_screenshot_command.is_some() || custom_click_command.is_some() {
+    if custom_screenshot_command.is_some()
+        || custom_click_command.is_some()
+        || custom_drag_command.is_some()
+    {
         Ok(Box::new(CustomInputCapture::new(
             Some(input_capture),
             custom_screenshot_command,
             custom_click_command,
+            custom_drag_command,
+            command_runner,
+            output_index,
         )?))
     } else {
         Ok(input_capture)
@@ -1,9 +1,18 @@
 #[cfg(target_os = "linux")]
+// only captured through with the `pipewire` feature, the session is built anyway so it's tested
+#[cfg_attr(not(feature = "pipewire"), allow(dead_code))]
+pub mod portal;
+#[cfg(target_os = "linux")]
 mod shmem;
 #[cfg(target_os = "linux")]
-mod wayland;
+pub mod uinput;
+#[cfg(target_os = "linux")]
+pub mod wayland;
 
+pub mod command;
+pub mod helper;
 pub mod input_capture_manager;
+pub mod replay;
 
 use anyhow::Result;
 use imageproc::image::DynamicImage;
@@ -11,4 +20,12 @@ use imageproc::image::DynamicImage;
 pub trait InputCaptureTrait {
     fn screenshot(&mut self) -> Result<DynamicImage>;
     fn click_at(&mut self, x: u32, y: u32) -> Result<()>;
+
+    // primitives for drag-and-drop moves
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()>;
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()>;
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()>;
+
+    // board square (e.g. "e4") the following pointer actions target, None when not on a square
+    fn set_target_square(&mut self, _square: Option<&str>) {}
 }
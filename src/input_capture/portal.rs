@@ -0,0 +1,751 @@
+// Capture and input through xdg-desktop-portal (ScreenCast + RemoteDesktop over D-Bus).
+// Used on Wayland compositors that don't offer the wlroots protocols, like GNOME and KDE.
+//
+// Frames come from the PipeWire stream the portal hands out, so this backend only captures with
+// the `pipewire` feature. The pointer goes through the RemoteDesktop session.
+
+#[cfg(feature = "pipewire")]
+use crate::input_capture::InputCaptureTrait;
+use anyhow::{anyhow, Context, Result};
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::LocalConnection;
+use dbus::message::MatchRule;
+use dbus::Path;
+#[cfg(feature = "pipewire")]
+use imageproc::image::DynamicImage;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const PORTAL_DESTINATION: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const REMOTE_DESKTOP_INTERFACE: &str = "org.freedesktop.portal.RemoteDesktop";
+const SCREEN_CAST_INTERFACE: &str = "org.freedesktop.portal.ScreenCast";
+const REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+
+const DEVICE_TYPE_POINTER: u32 = 2;
+const SOURCE_TYPE_MONITOR: u32 = 1;
+const LEFT_BUTTON: i32 = 0x110;
+
+const METHOD_TIMEOUT: Duration = Duration::from_secs(10);
+// the user may have to confirm a dialog before the portal responds
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// a stream handed out by the portal once the session started
+#[derive(Debug, Clone, Copy)]
+pub struct PortalStream {
+    pub node_id: u32,
+    pub size: Option<(i32, i32)>,
+}
+
+type Responses = Rc<RefCell<HashMap<String, (u32, PropMap)>>>;
+
+/// Negotiates a combined ScreenCast/RemoteDesktop session with the desktop portal.
+pub struct PortalSession {
+    connection: LocalConnection,
+    responses: Responses,
+    session: Path<'static>,
+    pub stream: PortalStream,
+    token_counter: u32,
+}
+
+impl PortalSession {
+    pub fn new() -> Result<Self> {
+        let connection =
+            LocalConnection::new_session().context("Failed to connect to the session bus")?;
+        Self::with_connection(connection)
+    }
+
+    fn with_connection(connection: LocalConnection) -> Result<Self> {
+        // responses arrive as signals on per-request objects, collect them all by path
+        let responses: Responses = Rc::new(RefCell::new(HashMap::new()));
+        let responses_handle = responses.clone();
+        connection.add_match(
+            MatchRule::new_signal(REQUEST_INTERFACE, "Response"),
+            move |(status, results): (u32, PropMap), _, message| {
+                if let Some(path) = message.path() {
+                    responses_handle
+                        .borrow_mut()
+                        .insert(path.to_string(), (status, results));
+                }
+                true
+            },
+        )?;
+
+        let mut session = Self {
+            connection,
+            responses,
+            session: Path::from("/"),
+            stream: PortalStream {
+                node_id: 0,
+                size: None,
+            },
+            token_counter: 0,
+        };
+        session.negotiate()?;
+        Ok(session)
+    }
+
+    fn negotiate(&mut self) -> Result<()> {
+        let mut options = self.request_options();
+        options.insert(
+            "session_handle_token".to_string(),
+            Variant(Box::new(format!("chust_session_{}", std::process::id()))),
+        );
+        let results = self.request(REMOTE_DESKTOP_INTERFACE, "CreateSession", |proxy| {
+            proxy.method_call(REMOTE_DESKTOP_INTERFACE, "CreateSession", (options,))
+        })?;
+        self.session = parse_session_handle(&results)?;
+
+        let mut options = self.request_options();
+        options.insert("types".to_string(), Variant(Box::new(DEVICE_TYPE_POINTER)));
+        let session = self.session.clone();
+        self.request(REMOTE_DESKTOP_INTERFACE, "SelectDevices", |proxy| {
+            proxy.method_call(
+                REMOTE_DESKTOP_INTERFACE,
+                "SelectDevices",
+                (session, options),
+            )
+        })?;
+
+        let mut options = self.request_options();
+        options.insert("types".to_string(), Variant(Box::new(SOURCE_TYPE_MONITOR)));
+        options.insert("multiple".to_string(), Variant(Box::new(false)));
+        let session = self.session.clone();
+        self.request(SCREEN_CAST_INTERFACE, "SelectSources", |proxy| {
+            proxy.method_call(SCREEN_CAST_INTERFACE, "SelectSources", (session, options))
+        })?;
+
+        let options = self.request_options();
+        let session = self.session.clone();
+        let results = self.request(REMOTE_DESKTOP_INTERFACE, "Start", |proxy| {
+            proxy.method_call(REMOTE_DESKTOP_INTERFACE, "Start", (session, "", options))
+        })?;
+
+        self.stream = parse_streams(&results)
+            .and_then(|streams| streams.into_iter().next())
+            .context("The portal did not return a screen cast stream")?;
+
+        Ok(())
+    }
+
+    fn request_options(&mut self) -> PropMap {
+        self.token_counter += 1;
+        let mut options = PropMap::new();
+        options.insert(
+            "handle_token".to_string(),
+            Variant(Box::new(format!("chust_{}", self.token_counter))),
+        );
+        options
+    }
+
+    /// Calls a portal method that returns a request object, then waits for its `Response` signal.
+    fn request<F>(&self, interface: &str, method: &str, call: F) -> Result<PropMap>
+    where
+        F: FnOnce(
+            &dbus::blocking::Proxy<'_, &LocalConnection>,
+        ) -> std::result::Result<(Path<'static>,), dbus::Error>,
+    {
+        let proxy = self
+            .connection
+            .with_proxy(PORTAL_DESTINATION, PORTAL_PATH, METHOD_TIMEOUT);
+        let (request_path,) =
+            call(&proxy).context(format!("Portal call {}.{} failed", interface, method))?;
+
+        let started_at = Instant::now();
+        loop {
+            if let Some((status, results)) = self
+                .responses
+                .borrow_mut()
+                .remove(&request_path.to_string())
+            {
+                return response_results(interface, method, status, results);
+            }
+            if started_at.elapsed() > RESPONSE_TIMEOUT {
+                return Err(anyhow!(
+                    "Timed out waiting for the portal to answer {}.{}",
+                    interface,
+                    method
+                ));
+            }
+            self.connection.process(Duration::from_millis(100))?;
+        }
+    }
+
+    pub fn pointer_motion_absolute(&self, x: f64, y: f64) -> Result<()> {
+        let proxy = self
+            .connection
+            .with_proxy(PORTAL_DESTINATION, PORTAL_PATH, METHOD_TIMEOUT);
+        proxy
+            .method_call::<(), _, _, _>(
+                REMOTE_DESKTOP_INTERFACE,
+                "NotifyPointerMotionAbsolute",
+                (
+                    self.session.clone(),
+                    PropMap::new(),
+                    self.stream.node_id,
+                    x,
+                    y,
+                ),
+            )
+            .context("Failed to move the pointer through the portal")?;
+        Ok(())
+    }
+
+    pub fn pointer_button(&self, pressed: bool) -> Result<()> {
+        let proxy = self
+            .connection
+            .with_proxy(PORTAL_DESTINATION, PORTAL_PATH, METHOD_TIMEOUT);
+        proxy
+            .method_call::<(), _, _, _>(
+                REMOTE_DESKTOP_INTERFACE,
+                "NotifyPointerButton",
+                (
+                    self.session.clone(),
+                    PropMap::new(),
+                    LEFT_BUTTON,
+                    pressed as u32,
+                ),
+            )
+            .context("Failed to press the pointer button through the portal")?;
+        Ok(())
+    }
+
+    pub fn open_pipewire_remote(&self) -> Result<std::os::fd::OwnedFd> {
+        use std::os::fd::FromRawFd;
+
+        let proxy = self
+            .connection
+            .with_proxy(PORTAL_DESTINATION, PORTAL_PATH, METHOD_TIMEOUT);
+        let (fd,): (dbus::arg::OwnedFd,) = proxy
+            .method_call(
+                SCREEN_CAST_INTERFACE,
+                "OpenPipeWireRemote",
+                (self.session.clone(), PropMap::new()),
+            )
+            .context("Failed to open the PipeWire remote")?;
+        Ok(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd.into_fd()) })
+    }
+}
+
+/// The results of a `Response` signal, or why the request didn't succeed.
+fn response_results(
+    interface: &str,
+    method: &str,
+    status: u32,
+    results: PropMap,
+) -> Result<PropMap> {
+    match status {
+        0 => Ok(results),
+        1 => Err(anyhow!("{}.{} was cancelled", interface, method)),
+        _ => Err(anyhow!("{}.{} failed", interface, method)),
+    }
+}
+
+/// Reads the `session_handle` entry of the `CreateSession` response.
+fn parse_session_handle(results: &PropMap) -> Result<Path<'static>> {
+    let session_handle = results
+        .get("session_handle")
+        .and_then(|handle| handle.as_str())
+        .context("The portal did not return a session handle")?;
+    Path::new(session_handle.to_string()).map_err(|err| anyhow!("Invalid session handle: {}", err))
+}
+
+/// Reads the `streams` entry (`a(ua{sv})`) of the `Start` response.
+fn parse_streams(results: &PropMap) -> Option<Vec<PortalStream>> {
+    let streams = results.get("streams")?;
+    let mut parsed = Vec::new();
+
+    for stream in streams.0.as_iter()? {
+        let mut fields = stream.as_iter()?;
+        let node_id = fields.next()?.as_u64()? as u32;
+        let mut size = None;
+
+        // dicts iterate as alternating keys and values
+        if let Some(mut properties) = fields.next().and_then(|p| p.as_iter()) {
+            while let (Some(key), Some(value)) = (properties.next(), properties.next()) {
+                if key.as_str() == Some("size") {
+                    if let [width, height, ..] = collect_integers(value)[..] {
+                        size = Some((width as i32, height as i32));
+                    }
+                }
+            }
+        }
+
+        parsed.push(PortalStream { node_id, size });
+    }
+
+    Some(parsed)
+}
+
+/// Flattens a (possibly variant-wrapped) struct of integers.
+fn collect_integers(arg: &dyn RefArg) -> Vec<i64> {
+    match arg.as_i64() {
+        Some(value) => vec![value],
+        None => arg
+            .as_iter()
+            .map(|items| items.flat_map(collect_integers).collect())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(feature = "pipewire")]
+pub struct InputCapturePortal {
+    session: PortalSession,
+    frames: pipewire_frames::FrameReceiver,
+
+    // size of the last screenshot, to map pixel coordinates onto the stream's coordinate space
+    frame_size: Option<(u32, u32)>,
+}
+
+#[cfg(feature = "pipewire")]
+impl InputCapturePortal {
+    pub fn new(output_index: usize) -> Result<Self> {
+        let session = PortalSession::new()?;
+
+        // the portal lets the user pick the monitor, so the output index is not used here
+        let _ = output_index;
+        let frames = pipewire_frames::FrameReceiver::start(
+            session.open_pipewire_remote()?,
+            session.stream.node_id,
+        )?;
+
+        Ok(Self {
+            session,
+            frames,
+            frame_size: None,
+        })
+    }
+
+    /// Maps screenshot pixel coordinates to the stream's (logical) coordinates.
+    fn to_stream_coordinates(&self, x: u32, y: u32) -> (f64, f64) {
+        match (self.session.stream.size, self.frame_size) {
+            (Some((stream_w, stream_h)), Some((frame_w, frame_h)))
+                if frame_w > 0 && frame_h > 0 =>
+            {
+                (
+                    x as f64 * stream_w as f64 / frame_w as f64,
+                    y as f64 * stream_h as f64 / frame_h as f64,
+                )
+            }
+            _ => (x as f64, y as f64),
+        }
+    }
+
+    fn pointer_event(&mut self, x: u32, y: u32, button: Option<bool>) -> Result<()> {
+        let (x, y) = self.to_stream_coordinates(x, y);
+        self.session.pointer_motion_absolute(x, y)?;
+        if let Some(pressed) = button {
+            self.session.pointer_button(pressed)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "pipewire")]
+impl InputCaptureTrait for InputCapturePortal {
+    fn screenshot(&mut self) -> Result<DynamicImage> {
+        let image = self.frames.latest_frame()?;
+
+        self.frame_size = Some((image.width(), image.height()));
+        Ok(image)
+    }
+
+    fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(true))?;
+        self.pointer_event(x, y, Some(false))
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(true))
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, None)
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(false))
+    }
+}
+
+#[cfg(feature = "pipewire")]
+mod pipewire_frames {
+    use anyhow::{anyhow, Result};
+    use imageproc::image::{DynamicImage, RgbImage};
+    use pipewire as pw;
+    use pw::spa;
+    use pw::spa::param::video::{VideoFormat, VideoInfoRaw};
+    use pw::spa::pod::Pod;
+    use std::os::fd::OwnedFd;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+    type SharedFrame = Arc<Mutex<Option<DynamicImage>>>;
+
+    /// Runs a PipeWire stream on a background thread and keeps the most recent frame.
+    pub struct FrameReceiver {
+        latest: SharedFrame,
+    }
+
+    impl FrameReceiver {
+        pub fn start(fd: OwnedFd, node_id: u32) -> Result<Self> {
+            let latest: SharedFrame = Arc::new(Mutex::new(None));
+            let latest_handle = latest.clone();
+
+            std::thread::spawn(move || {
+                if let Err(err) = run_stream(fd, node_id, latest_handle) {
+                    eprintln!("PipeWire stream stopped: {}", err);
+                }
+            });
+
+            Ok(Self { latest })
+        }
+
+        pub fn latest_frame(&self) -> Result<DynamicImage> {
+            let started_at = Instant::now();
+            loop {
+                if let Some(frame) = self.latest.lock().unwrap().as_ref() {
+                    return Ok(frame.clone());
+                }
+                if started_at.elapsed() > FIRST_FRAME_TIMEOUT {
+                    return Err(anyhow!("No frame received from the PipeWire stream"));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+
+    fn run_stream(fd: OwnedFd, node_id: u32, latest: SharedFrame) -> Result<()> {
+        pw::init();
+
+        let mainloop = pw::main_loop::MainLoop::new(None)?;
+        let context = pw::context::Context::new(&mainloop)?;
+        let core = context.connect_fd(fd, None)?;
+
+        let stream = pw::stream::Stream::new(
+            &core,
+            "chust-capture",
+            pw::properties::properties! {
+                *pw::keys::MEDIA_TYPE => "Video",
+                *pw::keys::MEDIA_CATEGORY => "Capture",
+                *pw::keys::MEDIA_ROLE => "Screen",
+            },
+        )?;
+
+        let _listener = stream
+            .add_local_listener_with_user_data(VideoInfoRaw::default())
+            .param_changed(|_, format, id, param| {
+                let Some(param) = param else {
+                    return;
+                };
+                if id != pw::spa::param::ParamType::Format.as_raw() {
+                    return;
+                }
+                let _ = format.parse(param);
+            })
+            .process(move |stream, format| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let datas = buffer.datas_mut();
+                if datas.is_empty() {
+                    return;
+                }
+
+                let data = &mut datas[0];
+                let stride = data.chunk().stride() as usize;
+                let size = format.size();
+                if let Some(bytes) = data.data() {
+                    if let Some(frame) =
+                        to_image(bytes, size.width, size.height, stride, format.format())
+                    {
+                        *latest.lock().unwrap() = Some(frame);
+                    }
+                }
+            })
+            .register()?;
+
+        let format = pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::Raw
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoFormat,
+                Choice,
+                Enum,
+                Id,
+                VideoFormat::BGRx,
+                VideoFormat::BGRx,
+                VideoFormat::RGBx,
+                VideoFormat::BGRA,
+                VideoFormat::RGBA,
+            ),
+        );
+        let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(format),
+        )
+        .map_err(|err| anyhow!("Failed to serialize the stream format: {:?}", err))?
+        .0
+        .into_inner();
+        let mut params =
+            [Pod::from_bytes(&values).ok_or_else(|| anyhow!("Invalid stream format"))?];
+
+        stream.connect(
+            spa::utils::Direction::Input,
+            Some(node_id),
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+
+        mainloop.run();
+        Ok(())
+    }
+
+    fn to_image(
+        bytes: &[u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: VideoFormat,
+    ) -> Option<DynamicImage> {
+        let bgr = match format {
+            VideoFormat::BGRx | VideoFormat::BGRA => true,
+            VideoFormat::RGBx | VideoFormat::RGBA => false,
+            _ => return None,
+        };
+        let stride = if stride == 0 {
+            width as usize * 4
+        } else {
+            stride
+        };
+        if bytes.len() < stride * height as usize {
+            return None;
+        }
+
+        let mut rgb_data = Vec::with_capacity((width * height * 3) as usize);
+        for row in bytes.chunks_exact(stride).take(height as usize) {
+            for pixel in row[..width as usize * 4].chunks_exact(4) {
+                if bgr {
+                    rgb_data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+                } else {
+                    rgb_data.extend_from_slice(&pixel[..3]);
+                }
+            }
+        }
+
+        RgbImage::from_raw(width, height, rgb_data).map(DynamicImage::ImageRgb8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::channel::{Channel, MatchingReceiver, Sender};
+    use dbus::Message;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+
+    const SESSION_HANDLE: &str = "/org/freedesktop/portal/desktop/session/1/chust";
+
+    fn start_results() -> PropMap {
+        let mut properties = PropMap::new();
+        properties.insert("size".to_string(), Variant(Box::new((1920, 1080))));
+        let mut results = PropMap::new();
+        results.insert(
+            "streams".to_string(),
+            Variant(Box::new(vec![(42u32, properties)])),
+        );
+        results
+    }
+
+    #[test]
+    fn parses_the_streams_of_start() {
+        let streams = parse_streams(&start_results()).unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].node_id, 42);
+        assert_eq!(streams[0].size, Some((1920, 1080)));
+    }
+
+    #[test]
+    fn parses_streams_without_a_size() {
+        let mut results = PropMap::new();
+        results.insert(
+            "streams".to_string(),
+            Variant(Box::new(vec![(7u32, PropMap::new())])),
+        );
+        let streams = parse_streams(&results).unwrap();
+        assert_eq!(streams[0].node_id, 7);
+        assert_eq!(streams[0].size, None);
+
+        assert!(parse_streams(&PropMap::new()).is_none());
+    }
+
+    #[test]
+    fn reads_the_session_handle() {
+        let mut results = PropMap::new();
+        assert!(parse_session_handle(&results).is_err());
+
+        results.insert(
+            "session_handle".to_string(),
+            Variant(Box::new("not a path".to_string())),
+        );
+        assert!(parse_session_handle(&results).is_err());
+
+        results.insert(
+            "session_handle".to_string(),
+            Variant(Box::new(SESSION_HANDLE.to_string())),
+        );
+        assert_eq!(&*parse_session_handle(&results).unwrap(), SESSION_HANDLE);
+    }
+
+    #[test]
+    fn reports_unsuccessful_responses() {
+        assert!(response_results("I", "M", 0, PropMap::new()).is_ok());
+        let cancelled = response_results("I", "M", 1, PropMap::new()).unwrap_err();
+        assert_eq!(cancelled.to_string(), "I.M was cancelled");
+        let failed = response_results("I", "M", 2, PropMap::new()).unwrap_err();
+        assert_eq!(failed.to_string(), "I.M failed");
+    }
+
+    /// A private bus, stopped on drop.
+    struct Bus {
+        daemon: Child,
+        address: String,
+        _config: tempfile::TempDir,
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// None when dbus-daemon isn't installed.
+    fn start_bus() -> Option<Bus> {
+        let config = tempfile::tempdir().unwrap();
+        let config_path = config.path().join("bus.conf");
+        std::fs::write(
+            &config_path,
+            format!(
+                "<busconfig><type>session</type><listen>unix:dir={}</listen>\
+                 <auth>EXTERNAL</auth><policy context=\"default\">\
+                 <allow send_destination=\"*\"/><allow receive_sender=\"*\"/><allow own=\"*\"/>\
+                 </policy></busconfig>",
+                config.path().display()
+            ),
+        )
+        .unwrap();
+
+        let mut daemon = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config_path.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(Bus {
+            daemon,
+            address: address.trim().to_string(),
+            _config: config,
+        })
+    }
+
+    fn connect(address: &str) -> LocalConnection {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        LocalConnection::from(channel)
+    }
+
+    /// Answers every portal call with a request object and its `Response`, and reports the
+    /// methods called.
+    fn stub_portal(address: String) -> mpsc::Receiver<String> {
+        let (calls, called) = mpsc::channel();
+        let (ready, is_ready) = mpsc::channel();
+        std::thread::spawn(move || {
+            let connection = connect(&address);
+            connection
+                .request_name(PORTAL_DESTINATION, false, false, true)
+                .unwrap();
+
+            let mut requests = 0;
+            connection.start_receive(
+                MatchRule::new_method_call(),
+                Box::new(move |message, connection| {
+                    let method = message.member().unwrap().to_string();
+                    let _ = calls.send(method.clone());
+                    requests += 1;
+                    let request = Path::from(format!("{}/request/{}", PORTAL_PATH, requests));
+                    let results = match method.as_str() {
+                        "CreateSession" => {
+                            let mut results = PropMap::new();
+                            results.insert(
+                                "session_handle".to_string(),
+                                Variant(Box::new(SESSION_HANDLE.to_string())),
+                            );
+                            results
+                        }
+                        "Start" => start_results(),
+                        _ => PropMap::new(),
+                    };
+                    connection
+                        .send(message.method_return().append1(&request))
+                        .unwrap();
+                    connection
+                        .send(
+                            Message::signal(
+                                &request,
+                                &REQUEST_INTERFACE.into(),
+                                &"Response".into(),
+                            )
+                            .append2(0u32, results),
+                        )
+                        .unwrap();
+                    true
+                }),
+            );
+            ready.send(()).unwrap();
+            while connection.process(Duration::from_millis(100)).is_ok() {}
+        });
+        is_ready.recv().unwrap();
+        called
+    }
+
+    #[test]
+    fn negotiates_a_session_with_the_portal() {
+        let Some(bus) = start_bus() else {
+            eprintln!("dbus-daemon isn't installed, skipping");
+            return;
+        };
+        let called = stub_portal(bus.address.clone());
+
+        let session = PortalSession::with_connection(connect(&bus.address)).unwrap();
+
+        assert_eq!(&*session.session, SESSION_HANDLE);
+        assert_eq!(session.stream.node_id, 42);
+        assert_eq!(session.stream.size, Some((1920, 1080)));
+        assert_eq!(
+            called.try_iter().collect::<Vec<_>>(),
+            ["CreateSession", "SelectDevices", "SelectSources", "Start"]
+        );
+    }
+}
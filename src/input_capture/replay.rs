@@ -0,0 +1,156 @@
+// Replays recorded frames instead of capturing the screen, and logs clicks to a file
+// instead of performing them. Useful for running `play` headless and deterministically.
+
+use crate::input_capture::InputCaptureTrait;
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{self, DynamicImage};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use tempfile::TempDir;
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "webp", "tiff"];
+
+pub struct ReplayInputCapture {
+    frames: Vec<PathBuf>,
+    next_frame: usize,
+
+    // when set, frames are served based on the elapsed time instead of one per screenshot
+    frame_interval: Option<f32>,
+    started_at: Option<Instant>,
+
+    click_log: File,
+
+    // directory holding frames extracted from a video file, removed on drop
+    _extracted_dir: Option<TempDir>,
+}
+
+impl ReplayInputCapture {
+    pub fn new(source: &str, frame_interval: Option<f32>, click_log_path: &str) -> Result<Self> {
+        let source_path = Path::new(source);
+        let (frames_dir, extracted_dir) = if source_path.is_dir() {
+            (source_path.to_path_buf(), None)
+        } else if source_path.is_file() {
+            let dir = extract_video_frames(source_path, None)?;
+            (dir.path().to_path_buf(), Some(dir))
+        } else {
+            return Err(anyhow!("Replay source `{}` does not exist", source));
+        };
+
+        let frames = collect_frames(&frames_dir)?;
+        if frames.is_empty() {
+            return Err(anyhow!("No frames found in `{}`", frames_dir.display()));
+        }
+
+        let click_log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(click_log_path)
+            .context(format!("Failed to open click log {}", click_log_path))?;
+
+        Ok(Self {
+            frames,
+            next_frame: 0,
+            frame_interval,
+            started_at: None,
+            click_log,
+            _extracted_dir: extracted_dir,
+        })
+    }
+
+    fn current_frame_index(&mut self) -> usize {
+        match self.frame_interval {
+            Some(interval) if interval > 0.0 => {
+                let started_at = *self.started_at.get_or_insert_with(Instant::now);
+                (started_at.elapsed().as_secs_f32() / interval) as usize
+            }
+            _ => {
+                let index = self.next_frame;
+                self.next_frame += 1;
+                index
+            }
+        }
+    }
+
+    fn log(&mut self, line: &str) -> Result<()> {
+        writeln!(self.click_log, "{}", line).context("Failed to write to click log")?;
+        self.click_log
+            .flush()
+            .context("Failed to flush click log")?;
+        Ok(())
+    }
+}
+
+impl InputCaptureTrait for ReplayInputCapture {
+    fn screenshot(&mut self) -> Result<DynamicImage> {
+        let index = self.current_frame_index();
+        let frame = self
+            .frames
+            .get(index)
+            .ok_or_else(|| anyhow!("Replay finished: no more frames"))?;
+
+        image::open(frame).context(format!("Failed to load frame {}", frame.display()))
+    }
+
+    fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.log(&format!("click {} {}", x, y))
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.log(&format!("press {} {}", x, y))
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.log(&format!("move {} {}", x, y))
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.log(&format!("release {} {}", x, y))
+    }
+}
+
+/// Returns the image files in `dir`, sorted by file name.
+pub fn collect_frames(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut frames: Vec<PathBuf> = fs::read_dir(dir)
+        .context(format!("Failed to read directory {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        })
+        .collect();
+    frames.sort();
+    Ok(frames)
+}
+
+/// Extracts the frames of a video file into a new temporary directory using ffmpeg,
+/// every frame or `fps` frames per second. The directory is removed when dropped.
+pub fn extract_video_frames(video: &Path, fps: Option<f32>) -> Result<TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix("chust-frames-")
+        .tempdir()
+        .context("Failed to create a temporary directory for the frames")?;
+
+    let mut command = Command::new("ffmpeg");
+    command.args(["-loglevel", "error", "-i"]).arg(video);
+    if let Some(fps) = fps {
+        command.arg("-vf").arg(format!("fps={}", fps));
+    }
+    let status = command
+        .arg(dir.path().join("frame_%06d.png"))
+        .stdout(Stdio::null())
+        .status()
+        .context("Failed to run ffmpeg. Is it installed?")?;
+
+    if !status.success() {
+        return Err(anyhow!(
+            "ffmpeg failed to extract frames from {}",
+            video.display()
+        ));
+    }
+
+    Ok(dir)
+}
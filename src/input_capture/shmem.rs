@@ -1,36 +1,46 @@
+use std::ffi::CString;
 use std::num::NonZeroUsize;
 use std::os::fd::OwnedFd;
+use std::sync::atomic::{AtomicU32, Ordering};
 
-use nix::fcntl::OFlag;
-use nix::sys::mman::{self, MapFlags, ProtFlags, shm_unlink};
-use nix::sys::stat::Mode;
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{self, MapFlags, ProtFlags};
 use nix::unistd::ftruncate;
 
-const FLINK_NAME: &str = "/shm_please_dont_exist";
+// memfd names don't have to be unique, but unique names make them easy to tell apart in /proc
+static SHMEM_COUNTER: AtomicU32 = AtomicU32::new(0);
 
-pub unsafe fn create_shmem(size: usize) -> (OwnedFd, *mut u8) {
-    let shm_fd = mman::shm_open(
-        FLINK_NAME,
-        OFlag::O_RDWR | OFlag::O_CREAT,
-        Mode::from_bits(0o666).unwrap(),
-    )
-    .unwrap();
-    shm_unlink(FLINK_NAME).unwrap();
+/// Creates an anonymous shared memory file of `size` bytes and maps it into memory.
+pub unsafe fn create_shmem(size: usize) -> nix::Result<(OwnedFd, *mut u8)> {
+    let name = CString::new(format!(
+        "chust-{}-{}",
+        std::process::id(),
+        SHMEM_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+    .expect("shared memory name contains no nul bytes");
+    let shm_fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)?;
 
-    ftruncate(&shm_fd, size as i64).unwrap();
+    ftruncate(&shm_fd, size as i64)?;
 
     let ptr: *mut u8 = unsafe {
         mman::mmap(
             None,
-            NonZeroUsize::new(size).unwrap(),
+            NonZeroUsize::new(size).ok_or(nix::Error::EINVAL)?,
             ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
             MapFlags::MAP_SHARED,
             &shm_fd,
             0,
-        )
-        .unwrap()
+        )?
         .as_ptr() as *mut u8
     };
 
-    return (shm_fd, ptr);
+    Ok((shm_fd, ptr))
+}
+
+/// Unmaps memory mapped by `create_shmem`.
+pub unsafe fn release_shmem(buffer: &[u8]) -> nix::Result<()> {
+    let Some(ptr) = std::ptr::NonNull::new(buffer.as_ptr() as *mut std::ffi::c_void) else {
+        return Ok(());
+    };
+    unsafe { mman::munmap(ptr, buffer.len()) }
 }
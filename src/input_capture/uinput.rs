@@ -0,0 +1,309 @@
+// Clicks through a virtual absolute pointer created with /dev/uinput. Works wherever the kernel
+// input stack does (GNOME Wayland, TTY kiosks, ...), but needs write access to /dev/uinput.
+// Screenshots are taken by another backend.
+
+use crate::input_capture::InputCaptureTrait;
+use anyhow::{anyhow, Context, Result};
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AbsInfo, AbsoluteAxisType, AttributeSet, EventType, InputEvent, Key, UinputAbsSetup};
+use imageproc::image::DynamicImage;
+use xcap::Monitor;
+
+pub const UINPUT_PATH: &str = "/dev/uinput";
+
+// resolution of the device axes, the compositor scales them onto the whole desktop
+const ABS_MAX: i32 = 65535;
+
+/// Maps screenshot pixels to device coordinates.
+///
+/// An absolute pointer covers the bounding box of all monitors, so we need to know where the
+/// captured monitor sits inside it (all in logical desktop coordinates).
+#[derive(Debug, Clone, Copy)]
+pub struct PointerCalibration {
+    pub desktop_x: i32,
+    pub desktop_y: i32,
+    pub desktop_width: u32,
+    pub desktop_height: u32,
+
+    pub monitor_x: i32,
+    pub monitor_y: i32,
+    pub monitor_width: u32,
+    pub monitor_height: u32,
+}
+
+impl PointerCalibration {
+    /// Parses `DESKTOP_WxDESKTOP_H[±DESKTOP_X±DESKTOP_Y]:MONITOR_WxMONITOR_H±MONITOR_X±MONITOR_Y`,
+    /// e.g. "3840x1080:1920x1080+1920+0" for the right monitor of two side by side, or
+    /// "3840x1080-1920+0:1920x1080-1920+0" for a monitor left of the primary one.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "Invalid calibration `{}`, expected DESKTOP_WxDESKTOP_H[±X±Y]:MONITOR_WxMONITOR_H±X±Y",
+                spec
+            )
+        };
+
+        let (desktop, monitor) = spec.split_once(':').ok_or_else(invalid)?;
+        let (desktop, desktop_offset) = split_offset(desktop);
+        let (desktop_width, desktop_height) = parse_size(desktop).ok_or_else(invalid)?;
+        let (desktop_x, desktop_y) = if desktop_offset.is_empty() {
+            (0, 0)
+        } else {
+            parse_offset(desktop_offset).ok_or_else(invalid)?
+        };
+
+        let (monitor, monitor_offset) = split_offset(monitor);
+        let (monitor_width, monitor_height) = parse_size(monitor).ok_or_else(invalid)?;
+        let (monitor_x, monitor_y) = parse_offset(monitor_offset).ok_or_else(invalid)?;
+
+        Ok(Self {
+            desktop_x,
+            desktop_y,
+            desktop_width,
+            desktop_height,
+            monitor_x,
+            monitor_y,
+            monitor_width,
+            monitor_height,
+        })
+    }
+
+    /// Reads the monitor layout from the system.
+    pub fn from_monitors(output_index: usize) -> Result<Self> {
+        let monitors = Monitor::all()?;
+        let mut geometry = Vec::with_capacity(monitors.len());
+        for monitor in &monitors {
+            geometry.push((
+                monitor.x()?,
+                monitor.y()?,
+                monitor.width()?,
+                monitor.height()?,
+            ));
+        }
+
+        let &(monitor_x, monitor_y, monitor_width, monitor_height) = geometry
+            .get(output_index)
+            .context("No monitor found for the uinput calibration")?;
+
+        let desktop_x = geometry.iter().map(|g| g.0).min().unwrap_or_default();
+        let desktop_y = geometry.iter().map(|g| g.1).min().unwrap_or_default();
+        let desktop_right = geometry
+            .iter()
+            .map(|g| g.0 + g.2 as i32)
+            .max()
+            .unwrap_or_default();
+        let desktop_bottom = geometry
+            .iter()
+            .map(|g| g.1 + g.3 as i32)
+            .max()
+            .unwrap_or_default();
+
+        Ok(Self {
+            desktop_x,
+            desktop_y,
+            desktop_width: (desktop_right - desktop_x) as u32,
+            desktop_height: (desktop_bottom - desktop_y) as u32,
+            monitor_x,
+            monitor_y,
+            monitor_width,
+            monitor_height,
+        })
+    }
+
+    /// Converts a pixel of a screenshot of `frame_size` into device coordinates.
+    pub fn device_position(&self, x: u32, y: u32, frame_size: (u32, u32)) -> (i32, i32) {
+        let (frame_width, frame_height) = frame_size;
+        // screenshots may be in physical pixels while the layout is in logical ones
+        let logical_x = self.monitor_x as f64
+            + x as f64 * self.monitor_width as f64 / frame_width.max(1) as f64;
+        let logical_y = self.monitor_y as f64
+            + y as f64 * self.monitor_height as f64 / frame_height.max(1) as f64;
+
+        let device_x =
+            (logical_x - self.desktop_x as f64) / self.desktop_width.max(1) as f64 * ABS_MAX as f64;
+        let device_y = (logical_y - self.desktop_y as f64) / self.desktop_height.max(1) as f64
+            * ABS_MAX as f64;
+
+        (
+            (device_x.round() as i32).clamp(0, ABS_MAX),
+            (device_y.round() as i32).clamp(0, ABS_MAX),
+        )
+    }
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.split_once('x')?;
+    Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+}
+
+/// Splits `WxH±X±Y` into the size and the offsets.
+fn split_offset(geometry: &str) -> (&str, &str) {
+    geometry.split_at(geometry.find(['+', '-']).unwrap_or(geometry.len()))
+}
+
+/// Parses signed offsets `±X±Y`.
+fn parse_offset(offset: &str) -> Option<(i32, i32)> {
+    // the sign of y starts the second number
+    let (x, y) = offset.split_at(offset.get(1..)?.find(['+', '-'])? + 1);
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+pub struct InputCaptureUinput {
+    device: VirtualDevice,
+    calibration: PointerCalibration,
+
+    // takes the screenshots
+    input_capture: Box<dyn InputCaptureTrait>,
+    frame_size: Option<(u32, u32)>,
+}
+
+impl InputCaptureUinput {
+    pub fn new(
+        input_capture: Box<dyn InputCaptureTrait>,
+        calibration: PointerCalibration,
+    ) -> Result<Self> {
+        let mut keys = AttributeSet::<Key>::new();
+        keys.insert(Key::BTN_LEFT);
+
+        let axis = |axis| UinputAbsSetup::new(axis, AbsInfo::new(0, 0, ABS_MAX, 0, 0, 0));
+
+        let device = VirtualDeviceBuilder::new()
+            .context(format!(
+                "Failed to open {}. Do you have write access to it?",
+                UINPUT_PATH
+            ))?
+            .name("chust virtual pointer")
+            .with_keys(&keys)?
+            .with_absolute_axis(&axis(AbsoluteAxisType::ABS_X))?
+            .with_absolute_axis(&axis(AbsoluteAxisType::ABS_Y))?
+            .build()
+            .context("Failed to create the uinput pointer device")?;
+
+        // give the compositor a moment to pick up the new device
+        std::thread::sleep(std::time::Duration::from_millis(500));
+
+        Ok(Self {
+            device,
+            calibration,
+            input_capture,
+            frame_size: None,
+        })
+    }
+
+    fn pointer_event(&mut self, x: u32, y: u32, button: Option<bool>) -> Result<()> {
+        let frame_size = self.frame_size.unwrap_or((
+            self.calibration.monitor_width,
+            self.calibration.monitor_height,
+        ));
+        let (device_x, device_y) = self.calibration.device_position(x, y, frame_size);
+
+        self.device.emit(&[
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, device_x),
+            InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, device_y),
+        ])?;
+        if let Some(pressed) = button {
+            self.device.emit(&[InputEvent::new(
+                EventType::KEY,
+                Key::BTN_LEFT.code(),
+                pressed as i32,
+            )])?;
+        }
+        Ok(())
+    }
+}
+
+impl InputCaptureTrait for InputCaptureUinput {
+    fn screenshot(&mut self) -> Result<DynamicImage> {
+        let image = self.input_capture.screenshot()?;
+        self.frame_size = Some((image.width(), image.height()));
+        Ok(image)
+    }
+
+    fn click_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(true))?;
+        self.pointer_event(x, y, Some(false))
+    }
+
+    fn press_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(true))
+    }
+
+    fn move_to(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, None)
+    }
+
+    fn release_at(&mut self, x: u32, y: u32) -> Result<()> {
+        self.pointer_event(x, y, Some(false))
+    }
+
+    fn set_target_square(&mut self, square: Option<&str>) {
+        self.input_capture.set_target_square(square);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_calibrations() {
+        let calibration = PointerCalibration::parse("3840x1080:1920x1080+1920+0").unwrap();
+        assert_eq!((calibration.desktop_x, calibration.desktop_y), (0, 0));
+        assert_eq!(
+            (calibration.desktop_width, calibration.desktop_height),
+            (3840, 1080)
+        );
+        assert_eq!((calibration.monitor_x, calibration.monitor_y), (1920, 0));
+        assert_eq!(
+            (calibration.monitor_width, calibration.monitor_height),
+            (1920, 1080)
+        );
+
+        let calibration = PointerCalibration::parse("3840x1080-1920+0:1920x1080-1920+0").unwrap();
+        assert_eq!((calibration.desktop_x, calibration.desktop_y), (-1920, 0));
+        assert_eq!((calibration.monitor_x, calibration.monitor_y), (-1920, 0));
+
+        let calibration = PointerCalibration::parse("1920x2160:1920x1080+0-1080").unwrap();
+        assert_eq!((calibration.monitor_x, calibration.monitor_y), (0, -1080));
+    }
+
+    #[test]
+    fn rejects_invalid_calibrations() {
+        for spec in [
+            "",
+            "3840x1080",
+            "3840x1080:1920x1080",
+            "3840x1080:1920x1080+1920",
+            "3840:1920x1080+0+0",
+            "3840x1080:1920xabc+0+0",
+            "3840x1080+0:1920x1080+0+0",
+            "3840x1080:1920x1080+a+0",
+        ] {
+            assert!(PointerCalibration::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn maps_pixels_onto_the_desktop() {
+        let calibration = PointerCalibration::parse("3840x1080:1920x1080+1920+0").unwrap();
+        assert_eq!(
+            calibration.device_position(0, 0, (1920, 1080)),
+            (ABS_MAX / 2 + 1, 0)
+        );
+        assert_eq!(
+            calibration.device_position(1920, 1080, (1920, 1080)),
+            (ABS_MAX, ABS_MAX)
+        );
+        // a screenshot in physical pixels of a monitor scaled by 2
+        assert_eq!(
+            calibration.device_position(1920, 1080, (3840, 2160)),
+            calibration.device_position(960, 540, (1920, 1080))
+        );
+
+        let calibration = PointerCalibration::parse("3840x1080-1920+0:1920x1080-1920+0").unwrap();
+        assert_eq!(
+            calibration.device_position(0, 540, (1920, 1080)),
+            (0, ABS_MAX / 2 + 1)
+        );
+    }
+}
@@ -1,26 +1,78 @@
 // ISSUES WITH THIS:
 // scale factor is not handeled
 // trnsformations are not handeled
-// not the best error handling
 
 // TODO:
 // add logging. instead of just ignoring th events, log them
 
 use crate::input_capture::shmem;
 
+use std::fmt;
 use std::os::fd::AsFd;
 
 use wayland_client::{
     delegate_noop,
     protocol::{wl_buffer, wl_output, wl_registry, wl_shm, wl_shm_pool},
-    Connection, Dispatch, EventQueue, QueueHandle,
+    ConnectError, Connection, Dispatch, DispatchError, EventQueue, Proxy, QueueHandle,
 };
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1;
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1;
 use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_manager_v1;
 use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1;
 
-use anyhow::Result;
+pub const SCREENCOPY_MANAGER: &str = "zwlr_screencopy_manager_v1";
+pub const VIRTUAL_POINTER_MANAGER: &str = "zwlr_virtual_pointer_manager_v1";
+pub const SHM: &str = "wl_shm";
+
+// formats we know how to turn into an image. (A|X)BGR8888 is R, G, B, X in memory,
+// (A|X)RGB8888 is B, G, R, X.
+const SUPPORTED_FORMATS: [wl_shm::Format; 4] = [
+    wl_shm::Format::Xbgr8888,
+    wl_shm::Format::Abgr8888,
+    wl_shm::Format::Xrgb8888,
+    wl_shm::Format::Argb8888,
+];
+
+#[derive(Debug)]
+pub enum WaylandError {
+    Connect(ConnectError),
+    Dispatch(DispatchError),
+    MissingGlobal(&'static str),
+    NoSuchOutput(usize),
+    UnsupportedFormat(Vec<String>),
+    FrameFailed,
+    SharedMemory(nix::Error),
+}
+
+impl fmt::Display for WaylandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaylandError::Connect(err) => write!(f, "Failed to connect to Wayland: {}", err),
+            WaylandError::Dispatch(err) => write!(f, "Wayland dispatch failed: {}", err),
+            WaylandError::MissingGlobal(global) => {
+                write!(f, "The compositor does not provide `{}`", global)
+            }
+            WaylandError::NoSuchOutput(index) => write!(f, "Output {} does not exist", index),
+            WaylandError::UnsupportedFormat(formats) => write!(
+                f,
+                "The compositor offered no supported buffer format (offered: {})",
+                formats.join(", ")
+            ),
+            WaylandError::FrameFailed => write!(f, "The compositor failed to copy the frame"),
+            WaylandError::SharedMemory(err) => {
+                write!(f, "Failed to create shared memory: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WaylandError {}
+
+impl From<DispatchError> for WaylandError {
+    fn from(err: DispatchError) -> Self {
+        WaylandError::Dispatch(err)
+    }
+}
 
 #[derive(Debug)]
 pub struct Output {
@@ -36,8 +88,17 @@ pub struct Output {
     pub buffer: Option<wl_buffer::WlBuffer>,
     pub readable_buffer: Option<&'static [u8]>,
 
+    // layout of the shared buffer, as negotiated with the compositor
+    pub buffer_format: Option<wl_shm::Format>,
+    pub buffer_width: u32,
+    pub buffer_height: u32,
+    pub buffer_stride: u32,
+    offered_formats: Vec<String>,
+
     pub ready_for_copy: bool,
     pub ready_for_read: bool,
+    // errors can't be returned from event handlers, so they are kept here until the next check
+    pub error: Option<WaylandError>,
 
     pub vp: Option<ZwlrVirtualPointerV1>,
 }
@@ -57,12 +118,30 @@ impl Output {
             buffer: None,
             readable_buffer: None,
 
+            buffer_format: None,
+            buffer_width: 0,
+            buffer_height: 0,
+            buffer_stride: 0,
+            offered_formats: Vec::new(),
+
             ready_for_copy: false,
             ready_for_read: false,
+            error: None,
 
             vp: None,
         }
     }
+
+    /// Destroys the shared buffer and unmaps its memory.
+    fn release_buffer(&mut self) -> nix::Result<()> {
+        if let Some(buffer) = self.buffer.take() {
+            buffer.destroy();
+        }
+        match self.readable_buffer.take() {
+            Some(readable_buffer) => unsafe { shmem::release_shmem(readable_buffer) },
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -74,6 +153,24 @@ pub struct State {
         Option<zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1>,
 
     pub outputs: Vec<Output>,
+
+    // every interface the compositor advertised, bound or not
+    pub globals: Vec<String>,
+}
+
+/// Connects to the compositor and collects its globals and outputs.
+pub fn connect() -> Result<(State, EventQueue<State>), WaylandError> {
+    let connection = Connection::connect_to_env().map_err(WaylandError::Connect)?;
+    let mut event_queue = connection.new_event_queue::<State>();
+    let qhandle = event_queue.handle();
+    let mut state = State::new();
+    connection.display().get_registry(&qhandle, ());
+
+    // the first roundtrip binds the globals, the second one receives the output events
+    event_queue.roundtrip(&mut state)?;
+    event_queue.roundtrip(&mut state)?;
+
+    Ok((state, event_queue))
 }
 
 impl State {
@@ -83,26 +180,35 @@ impl State {
             zwlr_screencopy_manager: None,
             zwlr_virtual_pointer_manager: None,
             outputs: Vec::new(),
+            globals: Vec::new(),
         }
     }
 
+    pub fn has_global(&self, interface: &str) -> bool {
+        self.globals.iter().any(|global| global == interface)
+    }
+
     pub fn create_new_vp(
         &mut self,
         output_index: usize,
         qh: &QueueHandle<Self>,
         eq: &mut EventQueue<Self>,
-    ) -> Result<()> {
+    ) -> Result<(), WaylandError> {
         let vp_manager = self
             .zwlr_virtual_pointer_manager
             .as_ref()
-            .expect("zwlr_virtual_pointer_manager not bound");
+            .ok_or(WaylandError::MissingGlobal(VIRTUAL_POINTER_MANAGER))?;
+        let output = self
+            .outputs
+            .get(output_index)
+            .ok_or(WaylandError::NoSuchOutput(output_index))?;
         let vp = Some(vp_manager.create_virtual_pointer_with_output(
             None,
-            self.outputs[output_index].wl_output.as_ref(),
+            output.wl_output.as_ref(),
             qh,
             (),
         ));
-        (&mut self.outputs[output_index]).vp = vp;
+        self.outputs[output_index].vp = vp;
 
         eq.roundtrip(self)?;
         Ok(())
@@ -113,39 +219,57 @@ impl State {
         output_index: usize,
         qh: &QueueHandle<Self>,
         eq: &mut EventQueue<Self>,
-    ) -> Result<()> {
-        let output = &mut self.outputs[output_index];
+    ) -> Result<(), WaylandError> {
+        let screencopy_manager = self
+            .zwlr_screencopy_manager
+            .as_ref()
+            .ok_or(WaylandError::MissingGlobal(SCREENCOPY_MANAGER))?;
+        let output = self
+            .outputs
+            .get_mut(output_index)
+            .ok_or(WaylandError::NoSuchOutput(output_index))?;
+        let wl_output = output
+            .wl_output
+            .as_ref()
+            .ok_or(WaylandError::NoSuchOutput(output_index))?;
+
         output.ready_for_copy = false;
         output.ready_for_read = false;
+        output.error = None;
+        output.offered_formats.clear();
 
         // create a new frame
-        if let Some(screencopy_manager) = &self.zwlr_screencopy_manager {
-            output.frame = Some(screencopy_manager.capture_output(
-                0,
-                output.wl_output.as_ref().expect("wl_output not bound"),
-                qh,
-                output_index,
-            ));
-        }
+        output.frame = Some(screencopy_manager.capture_output(0, wl_output, qh, output_index));
 
         // wait for the frame to be ready for sending the copy request
-        while self.outputs.first().map_or(true, |o| !o.ready_for_copy) {
-            eq.blocking_dispatch(self)?;
-        }
+        self.wait_for(output_index, eq, |o| o.ready_for_copy)?;
 
         let output = &mut self.outputs[output_index];
-        let frame = output.frame.as_ref().expect("frame not bound");
-        let buffer = output.buffer.as_ref().expect("buffer not bound");
-
-        // send the copy request
-        frame.copy(buffer);
+        if let (Some(frame), Some(buffer)) = (output.frame.as_ref(), output.buffer.as_ref()) {
+            // send the copy request
+            frame.copy(buffer);
+        }
 
         // wait for the frame to be ready for reading
-        while self.outputs.first().map_or(true, |o| !o.ready_for_read) {
+        self.wait_for(output_index, eq, |o| o.ready_for_read)
+    }
+
+    fn wait_for(
+        &mut self,
+        output_index: usize,
+        eq: &mut EventQueue<Self>,
+        ready: impl Fn(&Output) -> bool,
+    ) -> Result<(), WaylandError> {
+        loop {
+            let output = &mut self.outputs[output_index];
+            if let Some(err) = output.error.take() {
+                return Err(err);
+            }
+            if ready(output) {
+                return Ok(());
+            }
             eq.blocking_dispatch(self)?;
         }
-
-        Ok(())
     }
 }
 
@@ -159,7 +283,7 @@ delegate_noop!(State: ignore ZwlrVirtualPointerV1);
 impl Dispatch<zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, usize> for State {
     fn event(
         state: &mut Self,
-        _: &zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
+        frame: &zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
         event: zwlr_screencopy_frame_v1::Event,
         index: &usize,
         _: &Connection,
@@ -174,44 +298,87 @@ impl Dispatch<zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, usize> for State
                 height,
                 stride,
             } => {
-                // only accept Xbgr8888 format. This may cause some issues in the future.
-                if format.into_result().unwrap() != wl_shm::Format::Xbgr8888 {
+                // the frame may offer several formats, the first supported one wins
+                if output.ready_for_copy {
                     return;
                 }
 
-                // create a new buffer if we don't have one.
-                if output.wl_output.is_none() || output.buffer.is_none() {
+                let format = match format.into_result() {
+                    Ok(format) if SUPPORTED_FORMATS.contains(&format) => format,
+                    other => {
+                        output.offered_formats.push(format!("{:?}", other));
+                        // before version 3 there is only one buffer event per frame
+                        if frame.version() < 3 {
+                            output.error = Some(WaylandError::UnsupportedFormat(
+                                output.offered_formats.clone(),
+                            ));
+                        }
+                        return;
+                    }
+                };
+
+                // create a new buffer if we don't have one, or if the layout changed.
+                if output.buffer.is_none()
+                    || output.buffer_format != Some(format)
+                    || output.buffer_width != width
+                    || output.buffer_height != height
+                    || output.buffer_stride != stride
+                {
+                    let Some(wl_shm) = state.wl_shm.as_ref() else {
+                        output.error = Some(WaylandError::MissingGlobal(SHM));
+                        return;
+                    };
+                    if let Err(err) = output.release_buffer() {
+                        output.error = Some(WaylandError::SharedMemory(err));
+                        return;
+                    }
+
                     let buffer_size = (stride * height) as usize;
-                    let shm_fd = unsafe {
-                        let (shm_fd, ptr) = shmem::create_shmem(buffer_size);
-                        let readable_buffer = std::slice::from_raw_parts_mut(ptr, buffer_size);
-                        output.readable_buffer = Some(readable_buffer);
-                        shm_fd
+                    let shm_fd = match unsafe { shmem::create_shmem(buffer_size) } {
+                        Ok((shm_fd, ptr)) => {
+                            let readable_buffer =
+                                unsafe { std::slice::from_raw_parts_mut(ptr, buffer_size) };
+                            output.readable_buffer = Some(readable_buffer);
+                            shm_fd
+                        }
+                        Err(err) => {
+                            output.error = Some(WaylandError::SharedMemory(err));
+                            return;
+                        }
                     };
 
-                    let pool = state.wl_shm.as_ref().unwrap().create_pool(
-                        shm_fd.as_fd(),
-                        buffer_size as i32,
-                        qh,
-                        (),
-                    );
+                    let pool = wl_shm.create_pool(shm_fd.as_fd(), buffer_size as i32, qh, ());
 
                     output.buffer = Some(pool.create_buffer(
                         0,
                         width as i32,
                         height as i32,
                         stride as i32,
-                        wl_shm::Format::Xbgr8888,
+                        format,
                         qh,
                         (),
                     ));
+                    // the buffer keeps the memory alive, the pool isn't needed anymore
+                    pool.destroy();
+                    output.buffer_format = Some(format);
+                    output.buffer_width = width;
+                    output.buffer_height = height;
+                    output.buffer_stride = stride;
                 }
 
                 output.ready_for_copy = true;
             }
+            zwlr_screencopy_frame_v1::Event::BufferDone if !output.ready_for_copy => {
+                output.error = Some(WaylandError::UnsupportedFormat(
+                    output.offered_formats.clone(),
+                ));
+            }
             zwlr_screencopy_frame_v1::Event::Ready { .. } => {
                 output.ready_for_read = true;
             }
+            zwlr_screencopy_frame_v1::Event::Failed => {
+                output.error = Some(WaylandError::FrameFailed);
+            }
             _ => {}
         }
     }
@@ -259,24 +426,24 @@ impl Dispatch<wl_registry::WlRegistry, ()> for State {
         } = event
         {
             match &interface[..] {
-                "zwlr_virtual_pointer_manager_v1" => {
+                VIRTUAL_POINTER_MANAGER => {
                     state.zwlr_virtual_pointer_manager = Some(registry.bind(name, version, qh, ()));
                 }
-                "zwlr_screencopy_manager_v1" => {
+                SCREENCOPY_MANAGER => {
                     state.zwlr_screencopy_manager = Some(registry.bind(name, version, qh, ()));
                 }
                 "wl_output" => {
-                    let wl_output =
-                        Some(registry.bind(name, version, qh, state.outputs.len() as usize));
+                    let wl_output = Some(registry.bind(name, version, qh, state.outputs.len()));
                     let mut output = Output::new();
                     output.wl_output = wl_output;
                     state.outputs.push(output);
                 }
-                "wl_shm" => {
+                SHM => {
                     state.wl_shm = Some(registry.bind(name, version, qh, ()));
                 }
                 _ => {}
             }
+            state.globals.push(interface);
         }
     }
 }
@@ -1,47 +1,181 @@
 mod arg_parser;
+mod calibrate;
 mod chess_detection;
+mod classifier;
+mod doctor;
 mod drawing;
+mod frame_change;
+mod grid;
 mod input_capture;
+mod model_info;
 mod play;
+mod preprocess;
 mod process;
+mod profile;
+mod promotion;
+mod protocol;
+mod recognizer;
+mod recorder;
+mod serve;
 mod stockfish;
+mod template;
+mod temporal;
+mod video;
+mod yolo;
 
 use anyhow::{Context, Result};
-use arg_parser::Args;
+use arg_parser::{Args, ExecutionProvider, OptimizationLevel, Pov, PromotionMode, RecognizerKind};
 use chess_detection::ChessDetection;
-use clap::Parser;
-use ort::session::{builder::GraphOptimizationLevel, Session};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches};
+use classifier::SquareClassifier;
+use frame_change::FrameChangeDetector;
+use input_capture::command::CommandRunner;
+use ort::execution_providers::{
+    CPUExecutionProvider, ExecutionProvider as OrtExecutionProvider, ExecutionProviderDispatch,
+    OpenVINOExecutionProvider, XNNPACKExecutionProvider,
+};
+use ort::session::builder::{GraphOptimizationLevel, SessionBuilder};
+use ort::session::Session;
 use play::play;
 use process::process;
+use profile::Profile;
+use std::num::NonZeroUsize;
 use stockfish::Stockfish;
+use template::TemplateRecognizer;
+use temporal::TemporalFilter;
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    let chess_detector = initialize_chess_detector(&args)?;
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    // the profile knows the orientation unless --pov overrides it
+    let pov_given = matches.value_source("pov") == Some(ValueSource::CommandLine);
+
+    // doesn't need the model
+    if let arg_parser::Commands::Doctor = args.command {
+        return doctor::doctor();
+    }
+
+    if let arg_parser::Commands::Calibrate {
+        ref image_path,
+        ref output,
+        ref board,
+    } = args.command
+    {
+        // the model is only needed to find the board
+        let chess_detector = match board {
+            Some(_) => None,
+            None => Some(load_detection_model(&args, true)?),
+        };
+        return calibrate::calibrate(
+            image_path,
+            board.clone(),
+            output,
+            &args,
+            chess_detector.as_ref(),
+        );
+    }
+
+    let profile = args.profile.as_deref().map(Profile::load).transpose()?;
+    if let Some(profile) = &profile {
+        let profile_pov = if profile.white_pov { Pov::W } else { Pov::B };
+        if !pov_given {
+            args.pov = profile_pov;
+        } else if args.pov != profile_pov {
+            eprintln!(
+                "The profile was calibrated with {} at the bottom, leave out --pov to use that.",
+                if profile.white_pov { "white" } else { "black" },
+            );
+        }
+    }
+
+    if let arg_parser::Commands::Serve {
+        ref listen,
+        sessions,
+        max_body_size,
+    } = args.command
+    {
+        let chess_detectors = (0..sessions.max(1))
+            // every session would write the same optimised model
+            .map(|index| initialize_chess_detector(&args, profile.as_ref(), index == 0))
+            .collect::<Result<Vec<_>>>()?;
+        return serve::serve(listen, max_body_size, &args, chess_detectors);
+    }
+
+    let chess_detector = initialize_chess_detector(&args, profile.as_ref(), true)?;
 
     match args.command {
         arg_parser::Commands::Play {
             ref screenshot_command,
             ref click_command,
+            ref drag_command,
+            ref helper_command,
+            shell_commands,
+            command_timeout,
+            move_mode,
+            promotion_mode,
+            ref promotion_order,
             screenshot_delay,
             ref stockfish_path,
             stockfish_depth,
             recheck_after_change,
-            move_delay
+            stable_frames,
+            stable_ms,
+            max_board_shift,
+            change_threshold,
+            min_confidence,
+            move_delay,
+            move_retries,
+            ref replay,
+            replay_interval,
+            ref click_log,
+            ref record_dir,
+            record_changed_frames_only,
+            uinput,
+            ref uinput_calibration,
         } => {
             let input_capture = input_capture::input_capture_manager::create_input_capture(
                 0,
                 click_command.clone(),
                 screenshot_command.clone(),
+                drag_command.clone(),
+                helper_command.clone(),
+                replay.clone(),
+                replay_interval,
+                click_log,
+                uinput,
+                uinput_calibration.clone(),
+                CommandRunner::new(shell_commands, command_timeout),
             )?;
-            let stockfish = Stockfish::new(&stockfish_path)?;
+            let stockfish = Stockfish::new(stockfish_path)?;
+
+            // without a model nothing finds the dialog, and every promotion would wait on the prompt
+            let promotion_mode = if promotion_mode == PromotionMode::Dialog
+                && chess_detector.session().is_none()
+            {
+                eprintln!(
+                    "The promotion dialog can't be detected without a model, using --promotion-mode offset."
+                );
+                PromotionMode::Offset
+            } else {
+                promotion_mode
+            };
 
             play(
                 screenshot_delay,
                 stockfish_depth,
                 stockfish,
                 recheck_after_change,
+                TemporalFilter::new(stable_frames, stable_ms, max_board_shift),
+                FrameChangeDetector::new(change_threshold),
+                min_confidence,
                 move_delay,
+                move_retries,
+                move_mode,
+                promotion_mode,
+                promotion_order.clone(),
+                record_dir.clone(),
+                record_changed_frames_only,
                 &args,
                 &chess_detector,
                 input_capture,
@@ -52,36 +186,112 @@ fn main() -> Result<()> {
             ref image_path,
             no_fen,
             print_detections,
+            print_confidence,
             best_chessboard_detection_only,
             ref output_path,
             dont_exit,
+            framed,
         } => {
             process(
                 &image_path.to_string(),
                 no_fen,
                 print_detections,
+                print_confidence,
                 best_chessboard_detection_only,
                 output_path.clone(),
                 dont_exit,
+                framed,
+                &args,
+                &chess_detector,
+            )?;
+        }
+
+        arg_parser::Commands::Video {
+            ref source,
+            fps,
+            stable_frames,
+            stable_ms,
+            max_board_shift,
+            ref pgn,
+            ref timeline,
+        } => {
+            video::video(
+                source,
+                fps,
+                TemporalFilter::new(stable_frames, stable_ms, max_board_shift),
+                pgn.clone(),
+                timeline.clone(),
                 &args,
                 &chess_detector,
             )?;
         }
+
+        arg_parser::Commands::Doctor
+        | arg_parser::Commands::Serve { .. }
+        | arg_parser::Commands::Calibrate { .. } => unreachable!(),
     }
 
     Ok(())
 }
 
-fn initialize_chess_detector(args: &Args) -> Result<ChessDetection> {
+/// Loads the models and applies the profile. The detection model is saved to
+/// --save-optimized-model when `save_optimized_model` is set.
+fn initialize_chess_detector(
+    args: &Args,
+    profile: Option<&Profile>,
+    save_optimized_model: bool,
+) -> Result<ChessDetection> {
+    if args.recognizer == RecognizerKind::Template {
+        let profile = profile
+            .context("--recognizer template needs --profile, write one with `chust calibrate`")?;
+        return Ok(ChessDetection::without_model(
+            profile.board,
+            vec![Box::new(TemplateRecognizer::new(profile.templates.clone()))],
+        )
+        .with_board_colours(profile.light_square, profile.dark_square));
+    }
+
+    let mut chess_detector = load_detection_model(args, save_optimized_model)?;
+    if let Some(profile) = profile {
+        chess_detector = chess_detector
+            .with_board(profile.board)
+            .with_board_colours(profile.light_square, profile.dark_square);
+    }
+    if args.recognizer == RecognizerKind::Detection {
+        return Ok(chess_detector);
+    }
+    let classifier_path = args
+        .classifier_path
+        .as_ref()
+        .context("--recognizer classifier and ensemble need --classifier-path")?;
+    let classifier = session_builder(args, false)?
+        .commit_from_file(classifier_path)
+        .context(format!(
+            "Failed to load the classifier `{}`",
+            classifier_path
+        ))?;
+    let classifier = SquareClassifier::new(classifier).context(format!(
+        "The classifier `{}` isn't supported",
+        classifier_path
+    ))?;
+
+    Ok(chess_detector.with_recognizers(
+        args.recognizer == RecognizerKind::Ensemble,
+        vec![Box::new(classifier)],
+    ))
+}
+
+fn load_detection_model(args: &Args, save_optimized_model: bool) -> Result<ChessDetection> {
+    let model_path = if cfg!(feature = "embed_model") {
+        "embedded"
+    } else {
+        args.model_path.as_str()
+    };
     let model = if cfg!(feature = "embed_model") {
-        Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(2)?
+        session_builder(args, save_optimized_model)?
             .commit_from_memory(include_bytes!("../chess_detection.onnx"))?
     } else {
-        Session::builder()?
-            .with_optimization_level(GraphOptimizationLevel::Level3)?
-            .with_intra_threads(2)?
+        session_builder(args, save_optimized_model)?
             .commit_from_file(&args.model_path)
             .context(format!(
                 "Failed to load the model `{}`. Are you sure that the path is correct?",
@@ -89,5 +299,76 @@ fn initialize_chess_detector(args: &Args) -> Result<ChessDetection> {
             ))?
     };
 
-    Ok(ChessDetection::new(model, args.conf, args.refined_padding))
+    ChessDetection::new(
+        model,
+        args.conf,
+        args.iou,
+        args.refined_padding,
+        !args.no_grid_fit,
+    )
+    .context(format!("The model `{}` isn't supported", model_path))
+}
+
+/// A session builder configured by the command line. The optimised model is only written to
+/// --save-optimized-model when `save_optimized_model` is set.
+fn session_builder(args: &Args, save_optimized_model: bool) -> Result<SessionBuilder> {
+    let optimization_level = match args.optimization_level {
+        OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+        OptimizationLevel::Basic => GraphOptimizationLevel::Level1,
+        OptimizationLevel::Extended => GraphOptimizationLevel::Level2,
+        OptimizationLevel::All => GraphOptimizationLevel::Level3,
+    };
+
+    let execution_providers = args
+        .execution_provider
+        .iter()
+        .filter_map(|provider| match provider {
+            ExecutionProvider::Cpu => if_available(CPUExecutionProvider::default()),
+            ExecutionProvider::Xnnpack => {
+                let mut xnnpack = XNNPACKExecutionProvider::default();
+                if let Some(threads) = NonZeroUsize::new(args.intra_threads) {
+                    xnnpack = xnnpack.with_intra_op_num_threads(threads);
+                }
+                if_available(xnnpack)
+            }
+            ExecutionProvider::Openvino => if_available(OpenVINOExecutionProvider::default()),
+        })
+        .collect::<Vec<_>>();
+
+    let mut builder = Session::builder()?
+        .with_optimization_level(optimization_level)?
+        .with_execution_providers(execution_providers)?;
+    if args.intra_threads > 0 {
+        builder = builder.with_intra_threads(args.intra_threads)?;
+    }
+    if args.inter_threads > 0 {
+        builder = builder
+            .with_parallel_execution(true)?
+            .with_inter_threads(args.inter_threads)?;
+    }
+    if let Some(path) = args
+        .save_optimized_model
+        .as_ref()
+        .filter(|_| save_optimized_model)
+    {
+        builder = builder.with_optimized_model_path(path)?;
+    }
+
+    Ok(builder)
+}
+
+/// `provider` if the ONNX Runtime build has it. ONNX Runtime would skip it silently otherwise.
+fn if_available<P>(provider: P) -> Option<ExecutionProviderDispatch>
+where
+    P: OrtExecutionProvider + Into<ExecutionProviderDispatch>,
+{
+    if provider.is_available().unwrap_or(false) {
+        Some(provider.into())
+    } else {
+        eprintln!(
+            "The {} execution provider isn't part of this ONNX Runtime build, skipping it.",
+            provider.as_str()
+        );
+        None
+    }
 }
@@ -0,0 +1,424 @@
+// What a detection model expects and produces, read from its ONNX metadata so models trained
+// with other input sizes or class orders work without changes.
+//
+// Ultralytics exports store the class labels as a Python dict literal under `names`
+// (e.g. `{0: 'bP', 1: 'bR'}`) and the input size under `imgsz` (e.g. `[640, 640]`).
+
+use anyhow::{anyhow, Context, Result};
+use ort::session::Session;
+use ort::value::ValueType;
+
+/// Pieces in the order of their canonical class ids.
+pub static PIECE_MAP: [char; 12] = ['p', 'r', 'n', 'b', 'q', 'k', 'P', 'R', 'N', 'B', 'Q', 'K'];
+/// Canonical class id of the chessboard.
+pub const BOARD_CLASS: usize = 12;
+/// Labels of the canonical classes, used for models without a `names` entry.
+pub static DEFAULT_LABELS: [&str; 13] = [
+    "bP", "bR", "bN", "bB", "bQ", "bK", "wP", "wR", "wN", "wB", "wQ", "wK", "CB",
+];
+
+const DEFAULT_INPUT_SIZE: u32 = 640;
+// x1, y1, x2, y2, confidence, class
+const DETECTION_SIZE: i64 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Detections of shape [1, count, 6] (x1, y1, x2, y2, confidence, class), as exported with `nms=True`.
+    Detections,
+    /// The raw YOLOv8/v11 head of shape [1, 4 + classes, anchors] (center x, center y, width, height,
+    /// then a score per class), as exported by default.
+    RawHead,
+}
+
+pub struct ModelInfo {
+    pub input_name: String,
+    pub output_name: String,
+    pub output_format: OutputFormat,
+    /// Width and height of the images the model takes.
+    pub input_size: (u32, u32),
+    /// Labels by canonical class id: the pieces in `PIECE_MAP` order, the board,
+    /// then the model's other classes in their order.
+    pub labels: Vec<String>,
+    // canonical class id of every model class
+    class_map: Vec<f32>,
+}
+
+impl ModelInfo {
+    pub fn from_session(session: &Session) -> Result<Self> {
+        let [input] = session.inputs.as_slice() else {
+            return Err(anyhow!(
+                "The model has {} inputs, expected a single image input",
+                session.inputs.len()
+            ));
+        };
+        let output = session
+            .outputs
+            .first()
+            .context("The model has no outputs")?;
+
+        let input_dimensions = tensor_dimensions(&input.input_type)
+            .filter(|dimensions| dimensions.len() == 4 && matches!(dimensions[1], -1 | 3))
+            .context(format!(
+                "The model's input `{}` is {}, expected an image tensor of shape [1, 3, height, width]",
+                input.name, input.input_type
+            ))?;
+
+        let metadata = session
+            .metadata()
+            .context("Failed to read the model metadata")?;
+        let imgsz = metadata.custom("imgsz").ok().flatten();
+        let names = metadata.custom("names").ok().flatten();
+
+        let input_size = input_size(input_dimensions, imgsz.as_deref(), DEFAULT_INPUT_SIZE)?;
+        let (labels, class_map) = match names {
+            Some(names) => map_classes(&parse_names(&names)?)?,
+            None => (
+                DEFAULT_LABELS
+                    .iter()
+                    .map(|label| label.to_string())
+                    .collect(),
+                (0..DEFAULT_LABELS.len())
+                    .map(|class| class as f32)
+                    .collect(),
+            ),
+        };
+
+        let output_format = tensor_dimensions(&output.output_type)
+            .and_then(|dimensions| output_format(dimensions, class_map.len()))
+            .context(format!(
+                "The model's output `{}` is {}, expected detections of shape [1, count, 6] \
+                 (exported with `nms=True`) or a YOLO head of shape [1, {}, anchors] for its {} classes",
+                output.name,
+                output.output_type,
+                4 + class_map.len(),
+                class_map.len()
+            ))?;
+
+        Ok(Self {
+            input_name: input.name.clone(),
+            output_name: output.name.clone(),
+            output_format,
+            input_size,
+            labels,
+            class_map,
+        })
+    }
+
+    /// Canonical class id of a class the model reported. Unknown classes get an id
+    /// outside of the labels, so they are neither a piece nor the board.
+    pub fn canonical_class(&self, model_class: f32) -> f32 {
+        self.class_map
+            .get(model_class as usize)
+            .copied()
+            .unwrap_or(self.labels.len() as f32)
+    }
+}
+
+pub fn tensor_dimensions(value_type: &ValueType) -> Option<&[i64]> {
+    match value_type {
+        ValueType::Tensor { dimensions, .. } => Some(dimensions),
+        _ => None,
+    }
+}
+
+fn output_format(dimensions: &[i64], class_count: usize) -> Option<OutputFormat> {
+    let &[_, rows, columns] = dimensions else {
+        return None;
+    };
+    if columns == DETECTION_SIZE {
+        Some(OutputFormat::Detections)
+    } else if rows == 4 + class_count as i64 {
+        Some(OutputFormat::RawHead)
+    } else {
+        None
+    }
+}
+
+/// Fixed input dimensions win, `imgsz` fills in dynamic ones, `default` is the last resort.
+pub fn input_size(dimensions: &[i64], imgsz: Option<&str>, default: u32) -> Result<(u32, u32)> {
+    let imgsz = imgsz
+        .map(|imgsz| {
+            let sizes = imgsz
+                .trim_matches(|c| c == '[' || c == ']' || c == '(' || c == ')')
+                .split(',')
+                .map(|size| size.trim().parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .ok()
+                .filter(|sizes| sizes.iter().all(|&size| size > 0));
+            // imgsz is (height, width), or a single size for square inputs
+            match sizes.as_deref() {
+                Some(&[size]) => Ok((size, size)),
+                Some(&[height, width]) => Ok((width, height)),
+                _ => Err(anyhow!("Invalid `imgsz` in the model metadata: {}", imgsz)),
+            }
+        })
+        .transpose()?;
+
+    let (height, width) = (dimensions[2], dimensions[3]);
+    match (height, width, imgsz) {
+        (-1, -1, imgsz) => Ok(imgsz.unwrap_or((default, default))),
+        (-1, _, _) | (_, -1, _) => Err(anyhow!(
+            "The model's input has a dynamic {}, both sides should be either fixed or dynamic",
+            if height == -1 { "height" } else { "width" }
+        )),
+        (height, width, Some(imgsz)) if imgsz != (width as u32, height as u32) => Err(anyhow!(
+            "The model's input is {}x{} but its metadata says {}x{}",
+            width,
+            height,
+            imgsz.0,
+            imgsz.1
+        )),
+        (height, width, _) => Ok((width as u32, height as u32)),
+    }
+}
+
+/// Parses a Python dict literal of class ids to labels, e.g. `{0: 'bP', 1: "wK"}`.
+pub fn parse_names(names: &str) -> Result<Vec<String>> {
+    let invalid = || anyhow!("Invalid `names` in the model metadata: {}", names);
+
+    let mut entries = Vec::new();
+    let mut rest = names
+        .trim()
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .ok_or_else(invalid)?
+        .trim_start();
+    while !rest.is_empty() {
+        let (id, after_id) = rest.split_once(':').ok_or_else(invalid)?;
+        let id: usize = id.trim().parse().map_err(|_| invalid())?;
+
+        let after_id = after_id.trim_start();
+        let quote = after_id.chars().next().filter(|c| *c == '\'' || *c == '"');
+        let quote = quote.ok_or_else(invalid)?;
+        let (label, after_label) = after_id[1..].split_once(quote).ok_or_else(invalid)?;
+        entries.push((id, label.to_string()));
+
+        rest = after_label.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+
+    entries.sort();
+    if entries
+        .iter()
+        .enumerate()
+        .any(|(index, (id, _))| index != *id)
+    {
+        return Err(anyhow!(
+            "The class ids in the model's `names` should be 0 to {}: {}",
+            entries.len().saturating_sub(1),
+            names
+        ));
+    }
+    Ok(entries.into_iter().map(|(_, label)| label).collect())
+}
+
+/// Maps the model's labels to canonical class ids, all pieces and the board must be there.
+fn map_classes(model_labels: &[String]) -> Result<(Vec<String>, Vec<f32>)> {
+    let mut labels: Vec<Option<String>> = vec![None; BOARD_CLASS + 1];
+    let mut class_map = Vec::with_capacity(model_labels.len());
+
+    for label in model_labels {
+        let class = match label_to_class(label) {
+            Some(class) => {
+                if let Some(other) = &labels[class] {
+                    return Err(anyhow!(
+                        "The model's classes `{}` and `{}` are both {}",
+                        other,
+                        label,
+                        DEFAULT_LABELS[class]
+                    ));
+                }
+                labels[class] = Some(label.clone());
+                class
+            }
+            None => {
+                labels.push(Some(label.clone()));
+                labels.len() - 1
+            }
+        };
+        class_map.push(class as f32);
+    }
+
+    let missing: Vec<&str> = labels[..=BOARD_CLASS]
+        .iter()
+        .zip(DEFAULT_LABELS)
+        .filter(|(label, _)| label.is_none())
+        .map(|(_, default)| default)
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "The model has no class for {} (its classes are {})",
+            missing.join(", "),
+            model_labels.join(", ")
+        ));
+    }
+
+    Ok((labels.into_iter().flatten().collect(), class_map))
+}
+
+/// Recognizes labels such as `wK`, `K`, `white-king`, `black_pawn`, `CB` or `board`.
+pub fn label_to_class(label: &str) -> Option<usize> {
+    // a FEN letter, where the case gives the color
+    if let [c] = label.chars().collect::<Vec<_>>().as_slice() {
+        return PIECE_MAP.iter().position(|piece| piece == c);
+    }
+
+    let label: String = label
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if matches!(label.as_str(), "cb" | "board" | "chessboard") {
+        return Some(BOARD_CLASS);
+    }
+
+    let (is_white, piece) = if let Some(piece) = label.strip_prefix("white") {
+        (true, piece)
+    } else if let Some(piece) = label.strip_prefix("black") {
+        (false, piece)
+    } else if let Some(piece) = label.strip_prefix('w') {
+        (true, piece)
+    } else {
+        (false, label.strip_prefix('b')?)
+    };
+    let piece = match piece {
+        "p" | "pawn" => 'p',
+        "r" | "rook" => 'r',
+        "n" | "knight" => 'n',
+        "b" | "bishop" => 'b',
+        "q" | "queen" => 'q',
+        "k" | "king" => 'k',
+        _ => return None,
+    };
+    let piece = if is_white {
+        piece.to_ascii_uppercase()
+    } else {
+        piece
+    };
+    PIECE_MAP.iter().position(|&p| p == piece)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[&str]) -> Vec<String> {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_python_dicts() {
+        assert_eq!(
+            parse_names(r#"{1: "wK",0: 'bP' , 2:'CB',}"#).unwrap(),
+            ["bP", "wK", "CB"]
+        );
+        assert!(parse_names("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for names in [
+            "0: 'bP'",
+            "{0: bP}",
+            "{0: 'bP}",
+            "{zero: 'bP'}",
+            "{0: 'bP', 2: 'wK'}",
+            "{1: 'bP'}",
+            "{0: 'bP', 0: 'wK'}",
+        ] {
+            assert!(parse_names(names).is_err(), "{}", names);
+        }
+    }
+
+    #[test]
+    fn recognises_piece_and_board_labels() {
+        for (label, class) in [
+            ("bP", 0),
+            ("p", 0),
+            ("K", 11),
+            ("white-king", 11),
+            ("black_knight", 2),
+            ("WhiteQueen", 10),
+            ("wB", 9),
+            ("CB", BOARD_CLASS),
+            ("chess-board", BOARD_CLASS),
+        ] {
+            assert_eq!(label_to_class(label), Some(class), "{}", label);
+        }
+    }
+
+    #[test]
+    fn leaves_unknown_labels_alone() {
+        for label in ["x", "hand", "queen", "wX", "white", "red-king", ""] {
+            assert_eq!(label_to_class(label), None, "{}", label);
+        }
+    }
+
+    #[test]
+    fn maps_model_classes_to_canonical_ones() {
+        let mut model_labels = labels(&DEFAULT_LABELS);
+        model_labels.reverse();
+        model_labels.insert(3, "hand".to_string());
+
+        let (labels, class_map) = map_classes(&model_labels).unwrap();
+        assert_eq!(labels.len(), DEFAULT_LABELS.len() + 1);
+        assert_eq!(labels[BOARD_CLASS], "CB");
+        assert_eq!(labels[BOARD_CLASS + 1], "hand");
+        assert_eq!(class_map[0], BOARD_CLASS as f32);
+        assert_eq!(class_map[3], (BOARD_CLASS + 1) as f32);
+        assert_eq!(class_map[1], 11.0);
+    }
+
+    #[test]
+    fn requires_every_piece_and_the_board() {
+        let error = map_classes(&labels(&DEFAULT_LABELS[1..])).unwrap_err();
+        assert!(error.to_string().contains("no class for bP"), "{}", error);
+
+        let mut duplicated = labels(&DEFAULT_LABELS);
+        duplicated.push("black-pawn".to_string());
+        let error = map_classes(&duplicated).unwrap_err();
+        assert!(error.to_string().contains("are both bP"), "{}", error);
+    }
+
+    #[test]
+    fn tells_the_output_formats_apart() {
+        assert_eq!(
+            output_format(&[1, 300, 6], 13),
+            Some(OutputFormat::Detections)
+        );
+        assert_eq!(
+            output_format(&[1, -1, 6], 13),
+            Some(OutputFormat::Detections)
+        );
+        assert_eq!(
+            output_format(&[1, 17, 8400], 13),
+            Some(OutputFormat::RawHead)
+        );
+        assert_eq!(
+            output_format(&[1, 18, 8400], 14),
+            Some(OutputFormat::RawHead)
+        );
+        assert_eq!(output_format(&[1, 18, 8400], 13), None);
+        assert_eq!(output_format(&[17, 8400], 13), None);
+    }
+
+    #[test]
+    fn picks_the_input_size() {
+        assert_eq!(
+            input_size(&[1, 3, 480, 640], None, 320).unwrap(),
+            (640, 480)
+        );
+        assert_eq!(
+            input_size(&[1, 3, -1, -1], Some("[480, 640]"), 320).unwrap(),
+            (640, 480)
+        );
+        assert_eq!(
+            input_size(&[1, 3, -1, -1], Some("512"), 320).unwrap(),
+            (512, 512)
+        );
+        assert_eq!(input_size(&[1, 3, -1, -1], None, 320).unwrap(), (320, 320));
+        assert!(input_size(&[1, 3, -1, 640], None, 320).is_err());
+        assert!(input_size(&[1, 3, 480, 640], Some("[640, 640]"), 320).is_err());
+        assert!(input_size(&[1, 3, -1, -1], Some("[0, 640]"), 320).is_err());
+    }
+}
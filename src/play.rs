@@ -1,23 +1,58 @@
 use crate::{
-    arg_parser::Args,
+    arg_parser::{Args, MoveMode, PromotionMode},
     chess_detection::{get_best_chessboard_match, ChessDetection, DetectionLevel},
+    frame_change::FrameChangeDetector,
     input_capture::InputCaptureTrait,
+    promotion::handle_promotion,
+    recognizer::{square_rect, BoardReading},
+    recorder::SessionRecorder,
     stockfish::Stockfish,
+    temporal::TemporalFilter,
 };
 use anyhow::{Context, Result};
+use imageproc::image::DynamicImage;
 use ndarray::{ArrayBase, IxDyn, OwnedRepr};
-use std::io::{self, Read};
+use shakmaty::fen::Fen;
+use shakmaty::{Board, CastlingMode, Chess, Color, Piece, Position, PositionError, Role, Square};
+use std::time::Instant;
 
+const DRAG_STEPS: u32 = 10;
+// frames a position other than the expected one has to last after a move before it's believed
+const CHECK_FRAMES: usize = 3;
+// frames read after a move before giving up on telling what happened
+const MAX_CHECK_FRAMES: usize = 20;
+
+#[allow(clippy::too_many_arguments)]
 pub fn play(
     screenshot_delay: f32,
     stockfish_depth: u32,
     mut stockfish: Stockfish,
     recheck_after_change: bool,
+    mut temporal_filter: TemporalFilter,
+    mut frame_change: FrameChangeDetector,
+    min_confidence: f32,
     move_delay: f32,
+    move_retries: u32,
+    move_mode: MoveMode,
+    promotion_mode: PromotionMode,
+    promotion_order: String,
+    record_dir: Option<String>,
+    record_changed_frames_only: bool,
     args: &Args,
     chess_detector: &ChessDetection,
     mut input_capture: Box<dyn InputCaptureTrait>,
 ) -> Result<()> {
+    let mut recorder = record_dir
+        .map(|dir| {
+            SessionRecorder::new(
+                &dir,
+                record_changed_frames_only,
+                args.conf,
+                chess_detector.labels().to_vec(),
+            )
+        })
+        .transpose()?;
+
     let detection_level = if args.refined_search {
         DetectionLevel::Refined
     } else {
@@ -25,24 +60,37 @@ pub fn play(
     };
     let is_white_pov = args.pov == crate::arg_parser::Pov::W;
 
+    let started_at = Instant::now();
     let mut current_fen = "".to_string();
+    // a position seen while checking our move that is already ours to play
+    let mut next_position = None;
     loop {
-        let (_current_fen, detection) = wait_for_changes(
-            &current_fen,
-            &detection_level,
-            is_white_pov,
-            &mut input_capture,
-            chess_detector,
-            screenshot_delay,
-            recheck_after_change,
-        )?;
+        let (_current_fen, detection) = match next_position.take() {
+            Some(position) => position,
+            None => wait_for_changes(
+                &current_fen,
+                &detection_level,
+                is_white_pov,
+                &mut input_capture,
+                chess_detector,
+                screenshot_delay,
+                recheck_after_change,
+                &mut temporal_filter,
+                &mut frame_change,
+                min_confidence,
+                started_at,
+                &mut recorder,
+            )?,
+        };
         current_fen = _current_fen;
 
         let best_chessboard_match = get_best_chessboard_match(&detection).unwrap().0;
-        let board_cords = (
-            best_chessboard_match[0] as u32,
-            best_chessboard_match[1] as u32,
-        );
+        let board = [
+            best_chessboard_match[0],
+            best_chessboard_match[1],
+            best_chessboard_match[2],
+            best_chessboard_match[3],
+        ];
         let tile_size =
             ((best_chessboard_match[2] as u32 + best_chessboard_match[3] as u32) / 2) / 8;
 
@@ -59,32 +107,248 @@ pub fn play(
         );
 
         let best_move = stockfish.get_best_move(&fen, stockfish_depth)?;
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record_engine_reply(&fen, &best_move)?;
+        }
 
-        click_notation(
-            board_cords,
-            tile_size,
-            &best_move[0..2],
-            is_white_pov,
-            &mut input_capture,
-        )?;
-        std::thread::sleep(std::time::Duration::from_secs_f32(move_delay));
-        click_notation(
-            board_cords,
-            tile_size,
-            &best_move[2..4],
+        let expected_fen = stockfish.make_move_and_get_fen(&fen, &best_move)?;
+        let promotion_piece = best_move.chars().nth(4);
+        let unpromoted_fen = promotion_piece
+            .map(|_| unpromoted(&expected_fen, &best_move[2..4], is_white_pov))
+            .transpose()?;
+
+        let mut attempt = 0;
+        // the pawn is on the last rank, only the promotion is left to do
+        let mut pawn_moved = false;
+        current_fen = loop {
+            if !pawn_moved {
+                make_move(
+                    board,
+                    &best_move,
+                    is_white_pov,
+                    move_mode,
+                    move_delay,
+                    &mut input_capture,
+                )?;
+            }
+
+            // the prompt blocks, so only the first attempt may ask for the piece
+            if let Some(promotion_piece) =
+                promotion_piece.filter(|_| attempt == 0 || promotion_mode != PromotionMode::Manual)
+            {
+                let destination = notation_to_positions(board, &best_move[2..4], is_white_pov)
+                    .context("Invalid notation")?;
+                handle_promotion(
+                    promotion_mode,
+                    &promotion_order,
+                    promotion_piece,
+                    destination,
+                    tile_size,
+                    is_white_pov,
+                    screenshot_delay,
+                    attempt == 0,
+                    &detection_level,
+                    chess_detector,
+                    &mut input_capture,
+                )?;
+            }
+
+            match verify_move(
+                &current_fen,
+                &expected_fen,
+                unpromoted_fen.as_deref(),
+                &detection_level,
+                is_white_pov,
+                &mut input_capture,
+                chess_detector,
+                screenshot_delay,
+                &mut temporal_filter,
+                started_at,
+                &mut recorder,
+            )? {
+                MoveCheck::Confirmed => break expected_fen,
+                MoveCheck::Unchanged if attempt < move_retries => {
+                    attempt += 1;
+                    pawn_moved = false;
+                    println!(
+                        "Move {} was not registered, retrying ({}/{})...",
+                        best_move, attempt, move_retries
+                    );
+                }
+                MoveCheck::NotPromoted
+                    if attempt < move_retries && promotion_mode != PromotionMode::Manual =>
+                {
+                    attempt += 1;
+                    pawn_moved = true;
+                    println!(
+                        "The promotion of {} was not registered, retrying ({}/{})...",
+                        best_move, attempt, move_retries
+                    );
+                }
+                MoveCheck::Unchanged => {
+                    println!(
+                        "Move {} was not registered after {} retries, thinking again.",
+                        best_move, move_retries
+                    );
+                    break String::new();
+                }
+                MoveCheck::NotPromoted => {
+                    println!(
+                        "The promotion of {} was not registered, please promote by hand.",
+                        best_move
+                    );
+                    break expected_fen;
+                }
+                MoveCheck::Unreadable => {
+                    println!(
+                        "The board couldn't be read after {}, assuming the move was made.",
+                        best_move
+                    );
+                    break expected_fen;
+                }
+                MoveCheck::Mismatch(observed_fen, detection) => {
+                    if replied_to(&expected_fen, &observed_fen, is_white_pov) {
+                        println!("The opponent already replied to {}.", best_move);
+                        next_position = Some((observed_fen.clone(), detection));
+                    } else {
+                        println!(
+                            "The board does not match the expected position after {}, resynchronising from the observed board.",
+                            best_move
+                        );
+                    }
+                    break observed_fen;
+                }
+            }
+        };
+        // frames from before our move would outvote the opponent's reply
+        temporal_filter.reset();
+    }
+}
+
+/// The outcome of checking the board after making a move.
+enum MoveCheck {
+    /// The board shows the expected position.
+    Confirmed,
+    /// The board still shows the position before the move, so the move was probably lost.
+    Unchanged,
+    /// The pawn reached the last rank but wasn't promoted.
+    NotPromoted,
+    /// No position could be read for long enough to tell.
+    Unreadable,
+    /// The board steadily shows something else (e.g. the opponent already replied, or a misread).
+    Mismatch(String, ArrayBase<OwnedRepr<f32>, IxDyn>),
+}
+
+/// Re-captures the board after a move until it settles and compares it against the expected
+/// position. Frames that can't be read, e.g. while the piece is still moving, are skipped.
+#[allow(clippy::too_many_arguments)]
+fn verify_move(
+    previous_fen: &str,
+    expected_fen: &str,
+    unpromoted_fen: Option<&str>,
+    detection_level: &DetectionLevel,
+    is_white_pov: bool,
+    input_capture: &mut Box<dyn InputCaptureTrait>,
+    chess_detector: &ChessDetection,
+    screenshot_delay: f32,
+    temporal_filter: &mut TemporalFilter,
+    started_at: Instant,
+    recorder: &mut Option<SessionRecorder>,
+) -> Result<MoveCheck> {
+    temporal_filter.reset();
+    // the same stable position and how many frames in a row it was seen
+    let mut settled: Option<(String, usize)> = None;
+
+    for _ in 0..MAX_CHECK_FRAMES {
+        std::thread::sleep(std::time::Duration::from_secs_f32(screenshot_delay));
+
+        let screenshot = input_capture.screenshot()?;
+        let Ok((reading, detection)) = get_fen(
+            &screenshot,
+            chess_detector,
             is_white_pov,
-            &mut input_capture,
-        )?;
+            detection_level,
+            recorder,
+        ) else {
+            continue;
+        };
+        let Some((board, _)) = get_best_chessboard_match(&detection) else {
+            continue;
+        };
+        let board = [board[0], board[1], board[2], board[3]];
+        let Some(stable) =
+            temporal_filter.push(started_at.elapsed().as_millis() as u64, &reading.fen, board)
+        else {
+            continue;
+        };
 
-        if best_move.len() == 5 {
-            println!("Promotion move detected, promoting is not supported yet. Please manually promot to {} and press enter...", &best_move[4..5]);
-            io::stdin().read_exact(&mut [0])?;
+        if stable.fen == expected_fen {
+            return Ok(MoveCheck::Confirmed);
+        }
+        // anything else could be a frame of the animation, so it has to last
+        let frames = match settled.take() {
+            Some((fen, frames)) if fen == stable.fen => frames + 1,
+            _ => 1,
+        };
+        if frames < CHECK_FRAMES {
+            settled = Some((stable.fen, frames));
+            continue;
         }
 
-        current_fen = stockfish.make_move_and_get_fen(&fen, &best_move)?;
+        return Ok(if stable.fen == previous_fen {
+            MoveCheck::Unchanged
+        } else if Some(stable.fen.as_str()) == unpromoted_fen {
+            MoveCheck::NotPromoted
+        } else {
+            MoveCheck::Mismatch(stable.fen, detection)
+        });
     }
+
+    Ok(MoveCheck::Unreadable)
+}
+
+/// The expected position after a promotion, with the pawn still on the promotion square.
+fn unpromoted(expected_fen: &str, square: &str, is_white_pov: bool) -> Result<String> {
+    let mut board = Board::from_ascii_board_fen(expected_fen.as_bytes())
+        .context("Invalid FEN from Stockfish")?;
+    let square = Square::from_ascii(square.as_bytes()).context("Invalid notation")?;
+    board.set_piece_at(
+        square,
+        Piece {
+            color: Color::from_white(is_white_pov),
+            role: Role::Pawn,
+        },
+    );
+    Ok(board.to_string())
+}
+
+/// Whether one legal move of the opponent leads from `expected_fen`, the position after our move,
+/// to `observed_fen`.
+fn replied_to(expected_fen: &str, observed_fen: &str, is_white_pov: bool) -> bool {
+    let Ok(observed) = Board::from_ascii_board_fen(observed_fen.as_bytes()) else {
+        return false;
+    };
+    let fen = format!(
+        "{} {} KQkq - 0 1",
+        expected_fen,
+        if is_white_pov { "b" } else { "w" }
+    );
+    let Some(position) = Fen::from_ascii(fen.as_bytes()).ok().and_then(|fen| {
+        fen.into_position::<Chess>(CastlingMode::Standard)
+            .or_else(PositionError::ignore_invalid_castling_rights)
+            .ok()
+    }) else {
+        return false;
+    };
+
+    position.legal_moves().iter().any(|m| {
+        let mut after = position.clone();
+        after.play_unchecked(m);
+        after.board() == &observed
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn wait_for_changes(
     current_fen: &str,
     detection_level: &DetectionLevel,
@@ -94,16 +358,61 @@ fn wait_for_changes(
     screenshot_delay: f32,
 
     mut recheck_after_change: bool,
+    temporal_filter: &mut TemporalFilter,
+    frame_change: &mut FrameChangeDetector,
+    min_confidence: f32,
+    started_at: Instant,
+    recorder: &mut Option<SessionRecorder>,
 ) -> Result<(String, ArrayBase<OwnedRepr<f32>, IxDyn>)> {
+    let mut last_detection: Option<(BoardReading, _)> = None;
+    // the same uncertain board keeps coming back until the screen changes
+    let mut last_uncertain_fen = None;
     loop {
         std::thread::sleep(std::time::Duration::from_secs_f32(screenshot_delay));
 
-        let (fen, detection) = take_screenshot_and_get_fen(
-            input_capture,
-            chess_detector,
-            is_white_pov,
-            detection_level,
-        )?;
+        let screenshot = input_capture.screenshot()?;
+        // an unchanged board would be detected the same way again
+        let (reading, detection) = match last_detection.take() {
+            Some(last_detection) if !frame_change.changed(&screenshot) => last_detection,
+            _ => {
+                let (reading, detection) = get_fen(
+                    &screenshot,
+                    chess_detector,
+                    is_white_pov,
+                    detection_level,
+                    recorder,
+                )?;
+                if let Some((board, _)) = get_best_chessboard_match(&detection) {
+                    frame_change.update(&screenshot, [board[0], board[1], board[2], board[3]]);
+                }
+                if reading.confidence < min_confidence
+                    && last_uncertain_fen.as_ref() != Some(&reading.fen)
+                {
+                    println!(
+                        "Not acting on an uncertain board, waiting for a clearer screenshot.\n{}",
+                        reading.uncertainty_grid()
+                    );
+                    last_uncertain_fen = Some(reading.fen.clone());
+                }
+                (reading, detection)
+            }
+        };
+        if reading.confidence < min_confidence {
+            last_detection = Some((reading, detection));
+            continue;
+        }
+
+        let board = get_best_chessboard_match(&detection)
+            .context("Board not found")?
+            .0;
+        let board = [board[0], board[1], board[2], board[3]];
+        let stable =
+            temporal_filter.push(started_at.elapsed().as_millis() as u64, &reading.fen, board);
+        last_detection = Some((reading, detection.clone()));
+        let Some(stable) = stable else {
+            continue;
+        };
+        let fen = stable.fen;
 
         if fen == current_fen {
             continue;
@@ -118,51 +427,98 @@ fn wait_for_changes(
     }
 }
 
-fn take_screenshot_and_get_fen(
-    input_capture: &mut Box<dyn InputCaptureTrait>,
+fn get_fen(
+    screenshot: &DynamicImage,
     chess_detector: &ChessDetection,
     is_white_pov: bool,
     detection_level: &DetectionLevel,
-) -> Result<(String, ArrayBase<OwnedRepr<f32>, IxDyn>)> {
-    let screenshot = input_capture.screenshot()?;
+    recorder: &mut Option<SessionRecorder>,
+) -> Result<(BoardReading, ArrayBase<OwnedRepr<f32>, IxDyn>)> {
     let detection = chess_detector
-        .detect(&screenshot, &detection_level)
-        .context("Detection failed")?
-        .context("Board not found")?;
-    let best_chessboard_match = get_best_chessboard_match(&detection)
-        .context("Board not found")?
-        .0;
-    let fen = chess_detector.output_to_fen(
-        &detection,
-        (
-            best_chessboard_match[0] as u32,
-            best_chessboard_match[1] as u32,
-        ),
-        (
-            best_chessboard_match[2] as u32,
-            best_chessboard_match[3] as u32,
-        ),
-        is_white_pov,
-    );
-    Ok((fen, detection))
+        .detect(screenshot, detection_level)
+        .context("Detection failed")?;
+    let best_chessboard_match = match detection.as_ref().and_then(get_best_chessboard_match) {
+        Some(best_chessboard_match) => best_chessboard_match.0,
+        None => {
+            if let Some(recorder) = recorder.as_mut() {
+                recorder.record_frame(screenshot, detection.as_ref(), None)?;
+            }
+            return Err(anyhow::anyhow!("Board not found"));
+        }
+    };
+    let board = [
+        best_chessboard_match[0],
+        best_chessboard_match[1],
+        best_chessboard_match[2],
+        best_chessboard_match[3],
+    ];
+    let detection = detection.unwrap();
+    let reading = chess_detector.read_squares(screenshot, &detection, board, is_white_pov)?;
+
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.record_frame(screenshot, Some(&detection), Some(&reading.fen))?;
+    }
+
+    Ok((reading, detection))
+}
+
+/// Performs a move in UCI notation (e.g. "e2e4") by clicking or dragging, depending on `move_mode`.
+fn make_move(
+    board: [f32; 4],
+    best_move: &str,
+    is_white_pov: bool,
+    move_mode: MoveMode,
+    move_delay: f32,
+    input_capture: &mut Box<dyn InputCaptureTrait>,
+) -> Result<()> {
+    match move_mode {
+        MoveMode::Click => {
+            click_notation(board, &best_move[0..2], is_white_pov, input_capture)?;
+            std::thread::sleep(std::time::Duration::from_secs_f32(move_delay));
+            click_notation(board, &best_move[2..4], is_white_pov, input_capture)?;
+        }
+        MoveMode::Drag => {
+            let (from_x, from_y) = notation_to_positions(board, &best_move[0..2], is_white_pov)
+                .context("Invalid notation")?;
+            let (to_x, to_y) = notation_to_positions(board, &best_move[2..4], is_white_pov)
+                .context("Invalid notation")?;
+
+            input_capture.set_target_square(Some(&best_move[0..2]));
+            input_capture.press_at(from_x, from_y)?;
+            // move in small steps so the board UI sees a continuous drag
+            for step in 1..=DRAG_STEPS {
+                std::thread::sleep(std::time::Duration::from_secs_f32(
+                    move_delay / DRAG_STEPS as f32,
+                ));
+                let t = step as f32 / DRAG_STEPS as f32;
+                let x = from_x as f32 + (to_x as f32 - from_x as f32) * t;
+                let y = from_y as f32 + (to_y as f32 - from_y as f32) * t;
+                input_capture.move_to(x as u32, y as u32)?;
+            }
+            input_capture.set_target_square(Some(&best_move[2..4]));
+            input_capture.release_at(to_x, to_y)?;
+        }
+    }
+    input_capture.set_target_square(None);
+    Ok(())
 }
 
 fn click_notation(
-    board_cords: (u32, u32),
-    tile_size: u32,
+    board: [f32; 4],
     notation: &str,
     is_white_pov: bool,
     input_capture: &mut Box<dyn InputCaptureTrait>,
 ) -> Result<()> {
-    let (x, y) = notation_to_positions(board_cords, tile_size, notation, is_white_pov)
-        .context("Invalid notation")?;
+    let (x, y) =
+        notation_to_positions(board, notation, is_white_pov).context("Invalid notation")?;
+    input_capture.set_target_square(Some(notation));
     input_capture.click_at(x, y)?;
     Ok(())
 }
 
+/// Center of the square `notation` (e.g. "e4") on the board at `board` (x, y, width, height).
 fn notation_to_positions(
-    board_cords: (u32, u32),
-    tile_size: u32,
+    board: [f32; 4],
     notation: &str,
     is_white_pov: bool,
 ) -> Option<(u32, u32)> {
@@ -170,15 +526,15 @@ fn notation_to_positions(
         return None;
     }
 
-    let file = notation.chars().nth(0)?;
+    let file = notation.chars().next()?;
     let rank = notation.chars().nth(1)?.to_digit(10)?;
 
     if !('a'..='h').contains(&file) || !(1..=8).contains(&rank) {
         return None;
     }
 
-    let (board_start_x, board_start_y) = board_cords;
-    let file_index = (file as u8 - b'a') as u32;
+    let file_index = (file as u8 - b'a') as usize;
+    let rank = rank as usize;
 
     let (file_pos, rank_pos) = if is_white_pov {
         (file_index, 8 - rank)
@@ -186,8 +542,202 @@ fn notation_to_positions(
         ((7 - file_index), rank - 1)
     };
 
-    let x = board_start_x + (tile_size / 2) + (tile_size * file_pos);
-    let y = board_start_y + (tile_size / 2) + (tile_size * rank_pos);
+    let [x, y, width, height] = square_rect(board, rank_pos, file_pos);
+    Some(((x + width / 2.0) as u32, (y + height / 2.0) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arg_parser::Pov;
+    use crate::input_capture::replay::ReplayInputCapture;
+    use crate::template::{TemplateLibrary, TemplateRecognizer};
+    use clap::Parser;
+    use imageproc::drawing::draw_filled_rect_mut;
+    use imageproc::image::{Rgb, RgbImage};
+    use imageproc::rect::Rect;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    const BOARD: [f32; 4] = [8.0, 8.0, 320.0, 320.0];
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR";
+    const AFTER_E4: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR";
+
+    /// A board seen by white with every piece drawn as a bar of its own width, white pieces
+    /// outlined in black so they stand out from the light squares.
+    fn render(placement: &str) -> DynamicImage {
+        let square = (BOARD[2] / 8.0) as u32;
+        let mut image = RgbImage::from_pixel(336, 336, Rgb([40, 40, 40]));
+        for (row, rank) in placement.split('/').enumerate() {
+            let mut column = 0;
+            for piece in rank.chars() {
+                if let Some(empty) = piece.to_digit(10) {
+                    for _ in 0..empty {
+                        draw_square(&mut image, row, column, square, None);
+                        column += 1;
+                    }
+                } else {
+                    draw_square(&mut image, row, column, square, Some(piece));
+                    column += 1;
+                }
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+
+    fn draw_square(
+        image: &mut RgbImage,
+        row: usize,
+        column: usize,
+        square: u32,
+        piece: Option<char>,
+    ) {
+        let x = BOARD[0] as i32 + (column as u32 * square) as i32;
+        let y = BOARD[1] as i32 + (row as u32 * square) as i32;
+        let colour = if (row + column).is_multiple_of(2) {
+            Rgb([238, 238, 210])
+        } else {
+            Rgb([118, 150, 86])
+        };
+        draw_filled_rect_mut(image, Rect::at(x, y).of_size(square, square), colour);
 
-    Some((x, y))
+        let Some(piece) = piece else {
+            return;
+        };
+        let width = 6 + 4 * "pnbrqk".find(piece.to_ascii_lowercase()).unwrap() as u32;
+        let (x, y) = (x + (square - width) as i32 / 2, y + 6);
+        draw_filled_rect_mut(image, Rect::at(x, y).of_size(width, 28), Rgb([20, 20, 20]));
+        if piece.is_ascii_uppercase() {
+            draw_filled_rect_mut(
+                image,
+                Rect::at(x + 2, y + 2).of_size(width - 4, 24),
+                Rgb([250, 250, 250]),
+            );
+        }
+    }
+
+    /// An engine that always plays e2e4.
+    fn fake_engine(dir: &Path) -> String {
+        let path = dir.join("engine.sh");
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n\
+                 while read -r command rest; do\n\
+                 case \"$command\" in\n\
+                 go) echo 'bestmove e2e4' ;;\n\
+                 d) echo 'Fen: {} b KQkq - 0 1' ;;\n\
+                 quit) exit 0 ;;\n\
+                 esac\n\
+                 done\n",
+                AFTER_E4
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Plays on frames showing `positions` in turn and returns the logged clicks.
+    fn play_replay(positions: &[&str]) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let frames = dir.path().join("frames");
+        fs::create_dir(&frames).unwrap();
+        for (index, position) in positions.iter().enumerate() {
+            render(position)
+                .save(frames.join(format!("frame_{:02}.png", index)))
+                .unwrap();
+        }
+        let click_log = dir.path().join("clicks.log");
+
+        let library = TemplateLibrary::capture(&render(START), BOARD, true).unwrap();
+        let chess_detector =
+            ChessDetection::without_model(BOARD, vec![Box::new(TemplateRecognizer::new(library))]);
+        let args = Args::parse_from(["chust", "play"]);
+        assert_eq!(args.pov, Pov::W);
+        let input_capture =
+            ReplayInputCapture::new(frames.to_str().unwrap(), None, click_log.to_str().unwrap())
+                .unwrap();
+
+        let result = play(
+            0.0,
+            1,
+            Stockfish::new(&fake_engine(dir.path())).unwrap(),
+            false,
+            TemporalFilter::new(2, 0, 0.05),
+            FrameChangeDetector::new(2.0),
+            0.0,
+            0.0,
+            2,
+            MoveMode::Click,
+            PromotionMode::Offset,
+            "qnrb".to_string(),
+            None,
+            false,
+            &args,
+            &chess_detector,
+            Box::new(input_capture),
+        );
+
+        // play only stops when the replay runs out of frames
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("no more frames"), "{:#}", error);
+        fs::read_to_string(click_log)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn plays_the_engine_move_on_replayed_frames() {
+        let clicks = play_replay(&[START, START, AFTER_E4, AFTER_E4, AFTER_E4, AFTER_E4]);
+        // e2 then e4, the centres of their squares
+        assert_eq!(clicks, ["click 188 268", "click 188 188"]);
+    }
+
+    #[test]
+    fn retries_a_move_that_was_not_registered() {
+        let clicks = play_replay(&[
+            START, START, START, START, START, START, AFTER_E4, AFTER_E4, AFTER_E4,
+        ]);
+        assert_eq!(
+            clicks,
+            [
+                "click 188 268",
+                "click 188 188",
+                "click 188 268",
+                "click 188 188"
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_the_move_animation_to_finish() {
+        // the pawn is neither on e2 nor on e4 in the middle of the animation
+        let moving = "rnbqkbnr/pppppppp/8/8/8/8/PPPP1PPP/RNBQKBNR";
+        let clicks = play_replay(&[START, START, moving, AFTER_E4, AFTER_E4, AFTER_E4, AFTER_E4]);
+        assert_eq!(clicks, ["click 188 268", "click 188 188"]);
+    }
+
+    #[test]
+    fn recognises_the_opponent_reply() {
+        let after_e5 = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR";
+        assert!(replied_to(AFTER_E4, after_e5, true));
+        assert!(!replied_to(AFTER_E4, START, true));
+        assert!(!replied_to(AFTER_E4, AFTER_E4, true));
+    }
+
+    #[test]
+    fn puts_the_pawn_back_on_the_promotion_square() {
+        assert_eq!(
+            unpromoted("4Q2k/8/8/8/8/8/8/4K3", "e8", true).unwrap(),
+            "4P2k/8/8/8/8/8/8/4K3"
+        );
+        assert_eq!(
+            unpromoted("4k3/8/8/8/8/8/8/q3K3", "a1", false).unwrap(),
+            "4k3/8/8/8/8/8/8/p3K3"
+        );
+    }
 }
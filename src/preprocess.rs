@@ -0,0 +1,69 @@
+// Turns captured frames into the model's input tensor. This runs for every frame `play` looks at,
+// so it writes straight into a reused buffer instead of building intermediate images.
+
+use imageproc::image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+/// Letterboxes `img` into `input`, a planar RGB buffer of `input_size` (3 x height x width values
+/// in 0..1) padded with black. Returns the x and y offsets of the image in the input and its scale.
+pub fn letterbox_into(
+    img: &DynamicImage,
+    input_size: (u32, u32),
+    input: &mut [f32],
+) -> (u32, u32, f32) {
+    let (orig_w, orig_h) = img.dimensions();
+    let (target_w, target_h) = input_size;
+    assert_eq!(input.len(), 3 * target_w as usize * target_h as usize);
+
+    // Fit the image in the target size while maintaining aspect ratio
+    let scale = (target_w as f32 / orig_w as f32).min(target_h as f32 / orig_h as f32);
+    let new_w = ((orig_w as f32 * scale) as u32).clamp(1, target_w);
+    let new_h = ((orig_h as f32 * scale) as u32).clamp(1, target_h);
+
+    // Averaging areas is much faster than filtering when shrinking a lot, but aliases close to 1:1
+    let resized = if scale <= 0.5 {
+        img.thumbnail_exact(new_w, new_h)
+    } else {
+        img.resize_exact(new_w, new_h, FilterType::Triangle)
+    }
+    .into_rgb8();
+
+    // Center the image
+    let x_offset = (target_w - new_w) / 2;
+    let y_offset = (target_h - new_h) / 2;
+
+    input.fill(0.0);
+    let plane_size = target_w as usize * target_h as usize;
+    let (red, rest) = input.split_at_mut(plane_size);
+    let (green, blue) = rest.split_at_mut(plane_size);
+    for (y, row) in resized
+        .as_raw()
+        .chunks_exact(new_w as usize * 3)
+        .enumerate()
+    {
+        let start = (y + y_offset as usize) * target_w as usize + x_offset as usize;
+        let end = start + new_w as usize;
+        write_row(
+            row,
+            &mut red[start..end],
+            &mut green[start..end],
+            &mut blue[start..end],
+        );
+    }
+
+    (x_offset, y_offset, scale)
+}
+
+/// Splits a row of RGB pixels into the channel planes. Written so the compiler can vectorize it.
+fn write_row(row: &[u8], red: &mut [f32], green: &mut [f32], blue: &mut [f32]) {
+    const NORMALIZE: f32 = 1.0 / 255.0;
+    for (((pixel, r), g), b) in row
+        .chunks_exact(3)
+        .zip(red.iter_mut())
+        .zip(green.iter_mut())
+        .zip(blue.iter_mut())
+    {
+        *r = pixel[0] as f32 * NORMALIZE;
+        *g = pixel[1] as f32 * NORMALIZE;
+        *b = pixel[2] as f32 * NORMALIZE;
+    }
+}
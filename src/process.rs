@@ -1,18 +1,25 @@
 use crate::arg_parser::{Args, Pov};
 use crate::chess_detection::{get_best_chessboard_match, ChessDetection, DetectionLevel};
 use crate::drawing::annotate_detections;
+use crate::protocol::{self, Detection, Frame, FrameMetadata, Response};
+use crate::recognizer::BoardReading;
 use anyhow::{Context, Result};
-use imageproc::image::{self, DynamicImage};
+use imageproc::image;
 use ndarray::{ArrayBase, IxDyn, OwnedRepr};
-use std::io::{self, Cursor, Read, Write};
+use std::io::{self, Cursor, Write};
 
+pub type DetectionFilter = Box<dyn Fn(&[f32]) -> bool>;
+
+#[allow(clippy::too_many_arguments)]
 pub fn process(
     image_path: &str,
     no_fen: bool,
     print_detections: bool,
+    print_confidence: bool,
     best_chessboard_detection_only: bool,
     output_path: Option<String>,
     dont_exit: bool,
+    framed: bool,
 
     args: &Args,
     chess_detector: &ChessDetection,
@@ -23,42 +30,70 @@ pub fn process(
 
     let stdin = std::io::stdin();
     let mut handle = stdin.lock();
-
-    let detection_level = if args.refined_search {
-        DetectionLevel::Refined
-    } else {
-        DetectionLevel::Basic
-    };
+    let mut stdout = io::stdout();
+    let mut sequence = 0;
 
     loop {
-        let (is_white_pov, mut image) = if image_path != "-" {
-            (
-                args.pov == Pov::W,
-                image::open(image_path).context(format!("Failed to load image {}", image_path))?,
-            )
+        let frame = if image_path != "-" {
+            Frame {
+                is_white_pov: Some(args.pov == Pov::W),
+                image: image::open(image_path)
+                    .context(format!("Failed to load image {}", image_path))?,
+                metadata: FrameMetadata::default(),
+            }
+        } else if framed {
+            let raw_frame = match protocol::read_raw_frame(&mut handle) {
+                Ok(Some(raw_frame)) => raw_frame,
+                Ok(None) => break,
+                Err(err) => {
+                    // we can't find the next frame after a broken one
+                    write_error(&mut stdout, None, sequence, &err)?;
+                    return Err(err);
+                }
+            };
+            match raw_frame.decode() {
+                Ok(frame) => frame,
+                Err(err) => {
+                    write_error(&mut stdout, None, sequence, &err)?;
+                    sequence += 1;
+                    continue;
+                }
+            }
         } else {
-            read_image_from_stdin(&mut handle)?
+            protocol::read_frame(&mut handle)?
         };
 
-        let detections = chess_detector
-            .detect(&image, &detection_level)
-            .context("Detection failed")?
-            .context("Failed to find the chessboard")?;
-
-        let detection_filter = process_detections_and_generate_filter(
-            &chess_detector,
-            &args,
-            &detections,
-            is_white_pov,
+        let frame_id = frame.metadata.frame_id;
+        let result = process_frame(
+            frame,
             no_fen,
             print_detections,
+            print_confidence,
             best_chessboard_detection_only,
-        )?;
+            output_path.as_deref(),
+            framed,
+            args,
+            chess_detector,
+        );
 
-        if let Some(output_path) = output_path.as_ref() {
-            annotate_detections(&mut image, &detections, &detection_filter);
-            save_image(&image, output_path)?;
+        if framed {
+            match result {
+                Ok((response, payload)) => protocol::write_response(
+                    &mut stdout,
+                    &Response {
+                        frame_id,
+                        sequence,
+                        ok: true,
+                        ..response
+                    },
+                    &payload,
+                )?,
+                Err(err) => write_error(&mut stdout, frame_id, sequence, &err)?,
+            }
+        } else {
+            result?;
         }
+        sequence += 1;
 
         if image_path != "-" || !dont_exit {
             break;
@@ -68,31 +103,156 @@ pub fn process(
     Ok(())
 }
 
-fn process_detections_and_generate_filter(
+/// Detects the board in one frame and prints the results, or returns them when `framed`
+/// together with the annotated image if it should go to stdout.
+#[allow(clippy::too_many_arguments)]
+fn process_frame(
+    frame: Frame,
+    no_fen: bool,
+    print_detections: bool,
+    print_confidence: bool,
+    best_chessboard_detection_only: bool,
+    output_path: Option<&str>,
+    framed: bool,
+
+    args: &Args,
+    chess_detector: &ChessDetection,
+) -> Result<(Response, Vec<u8>)> {
+    let Frame {
+        is_white_pov,
+        mut image,
+        metadata,
+    } = frame;
+    let is_white_pov = is_white_pov.unwrap_or(args.pov == Pov::W);
+
+    // the frame's metadata overrides the command line
+    let no_fen = metadata.no_fen.unwrap_or(no_fen);
+    let print_detections = metadata.print_detections.unwrap_or(print_detections);
+    let best_chessboard_detection_only = metadata
+        .best_chessboard_detection_only
+        .unwrap_or(best_chessboard_detection_only);
+    let detection_level = if metadata.refined_search.unwrap_or(args.refined_search) {
+        DetectionLevel::Refined
+    } else {
+        DetectionLevel::Basic
+    };
+
+    let detections = chess_detector
+        .detect(&image, &detection_level)
+        .context("Detection failed")?
+        .context("Failed to find the chessboard")?;
+
+    let (detection_filter, reading) = process_detections_and_generate_filter(
+        chess_detector,
+        args,
+        &image,
+        &detections,
+        is_white_pov,
+        no_fen,
+        best_chessboard_detection_only,
+    )?;
+
+    let mut response = Response::default();
+    if framed {
+        response.confidence = reading.as_ref().map(|reading| reading.confidence);
+        response.fen = reading.map(|reading| reading.fen);
+        if print_detections {
+            response.detections = Some(
+                detections
+                    .axis_iter(ndarray::Axis(0))
+                    .filter_map(|row| {
+                        let row = row.to_slice()?;
+                        detection_filter(row).then(|| Detection::from_row(row))
+                    })
+                    .collect(),
+            );
+        }
+    } else {
+        if let Some(frame_id) = metadata.frame_id {
+            println!("Frame: {}", frame_id);
+        }
+        if let Some(reading) = reading {
+            println!("FEN: {}\n", reading.fen);
+            if print_confidence {
+                println!("{}", reading.uncertainty_grid());
+            }
+        }
+        if print_detections {
+            detections.axis_iter(ndarray::Axis(0)).for_each(|row| {
+                if detection_filter(row.to_slice().unwrap()) {
+                    println!(
+                        "{}: {}, {}, {}, {}, {}",
+                        row[5], row[0], row[1], row[2], row[3], row[4]
+                    );
+                }
+            });
+            println!();
+        }
+    }
+
+    let mut payload = Vec::new();
+    if let Some(output_path) = output_path {
+        annotate_detections(
+            &mut image,
+            &detections,
+            chess_detector.labels(),
+            &detection_filter,
+        );
+        if framed && output_path == "-" {
+            payload = encode_png(&image)?;
+            response.image_format = Some("png");
+        } else {
+            save_image(&image, output_path)?;
+        }
+    }
+
+    Ok((response, payload))
+}
+
+fn write_error(
+    stdout: &mut io::Stdout,
+    frame_id: Option<u64>,
+    sequence: u64,
+    err: &anyhow::Error,
+) -> Result<()> {
+    protocol::write_response(
+        &mut stdout.lock(),
+        &Response {
+            frame_id,
+            sequence,
+            ok: false,
+            error: Some(format!("{:#}", err)),
+            ..Default::default()
+        },
+        &[],
+    )
+}
+
+pub fn process_detections_and_generate_filter(
     chess_detector: &ChessDetection,
     args: &Args,
+    image: &image::DynamicImage,
     detections: &ArrayBase<OwnedRepr<f32>, IxDyn>,
     is_white_pov: bool,
     no_fen: bool,
-    print_detections: bool,
     best_chessboard_detection_only: bool,
-) -> Result<Box<dyn Fn(&[f32]) -> bool>> {
+) -> Result<(DetectionFilter, Option<BoardReading>)> {
     let confidence_threshold = args.conf;
-    let mut detection_filter: Box<dyn Fn(&[f32]) -> bool> =
+    let mut detection_filter: DetectionFilter =
         Box::new(move |row: &[f32]| row[4] >= confidence_threshold);
 
+    let mut reading = None;
     if !no_fen {
         let best_match = get_best_chessboard_match(detections)
             .context("No chessboard found")?
             .0;
 
-        let fen = chess_detector.output_to_fen(
+        reading = Some(chess_detector.read_squares(
+            image,
             detections,
-            (best_match[0] as u32, best_match[1] as u32),
-            (best_match[2] as u32, best_match[3] as u32),
+            [best_match[0], best_match[1], best_match[2], best_match[3]],
             is_white_pov,
-        );
-        println!("FEN: {}\n", fen);
+        )?);
 
         if best_chessboard_detection_only {
             let (x, y, width, height) = (
@@ -122,30 +282,21 @@ fn process_detections_and_generate_filter(
         }
     }
 
-    if print_detections {
-        detections.axis_iter(ndarray::Axis(0)).for_each(|row| {
-            if detection_filter(row.to_slice().unwrap()) {
-                println!(
-                    "{}: {}, {}, {}, {}, {}",
-                    row[5], row[0], row[1], row[2], row[3], row[4]
-                );
-            }
-        });
-        println!("");
-    }
+    Ok((detection_filter, reading))
+}
 
-    Ok(detection_filter)
+fn encode_png(img: &image::DynamicImage) -> Result<Vec<u8>> {
+    let mut buffer = Cursor::new(Vec::new());
+    img.write_to(&mut buffer, image::ImageFormat::Png)
+        .context("Failed to encode image")?;
+    Ok(buffer.into_inner())
 }
 
 fn save_image(img: &image::DynamicImage, output_path: &str) -> anyhow::Result<()> {
     if output_path == "-" {
-        let mut buffer = Cursor::new(Vec::new());
-        img.write_to(&mut buffer, image::ImageFormat::Png)
-            .context("Failed to write image to stdout")?;
-
         io::stdout()
             .lock()
-            .write_all(&buffer.into_inner())
+            .write_all(&encode_png(img)?)
             .context("Failed to write image to stdout")?;
     } else {
         img.save(output_path)
@@ -153,25 +304,3 @@ fn save_image(img: &image::DynamicImage, output_path: &str) -> anyhow::Result<()
     }
     Ok(())
 }
-
-fn read_image_from_stdin(handle: &mut impl Read) -> Result<(bool, DynamicImage)> {
-    let mut pov_buffer = [0u8; 1];
-    handle
-        .read_exact(&mut pov_buffer)
-        .context("Reading POV failed")?;
-    let is_white_pov = pov_buffer[0] == 1;
-
-    let mut image_size_buffer = [0u8; 4];
-    handle
-        .read_exact(&mut image_size_buffer)
-        .context("Reading image size failed")?;
-    let image_size = u32::from_ne_bytes(image_size_buffer);
-
-    let mut image_data = vec![0u8; image_size as usize];
-    handle
-        .read_exact(&mut image_data)
-        .context("Reading image data failed")?;
-    let img = image::load_from_memory(&image_data).context("Failed to load image from memory")?;
-
-    Ok((is_white_pov, img))
-}
@@ -0,0 +1,154 @@
+// What `chust calibrate` learns about a board from a screenshot of the starting position. Loading
+// it with --profile skips looking for the board, which has to stay at the same place on screen,
+// and sets --pov unless it is given. The colours of the squares tell whether it's still there.
+
+use crate::recognizer::square_rect;
+use crate::template::TemplateLibrary;
+use anyhow::{Context, Result};
+use imageproc::image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// largest difference (0-255) of a channel from the calibrated colour of the squares
+const COLOUR_TOLERANCE: u8 = 24;
+
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    /// The board (x, y, width, height) fitted to its square grid.
+    pub board: [f32; 4],
+    /// Average RGB colour of the light squares.
+    pub light_square: [u8; 3],
+    /// Average RGB colour of the dark squares.
+    pub dark_square: [u8; 3],
+    /// Whether white was at the bottom.
+    pub white_pov: bool,
+    pub templates: TemplateLibrary,
+}
+
+impl Profile {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = fs::read_to_string(path).context(format!(
+            "Failed to read the profile `{}`. Write one with `chust calibrate`",
+            path
+        ))?;
+        serde_json::from_str(&contents).context(format!("`{}` isn't a board profile", path))
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string(self)?)
+            .context(format!("Failed to write the profile to `{}`", path))
+    }
+}
+
+/// Whether the squares of the board at `board` (x, y, width, height) have the colours
+/// `light_square` and `dark_square`, i.e. the board didn't move and its theme didn't change.
+pub fn square_colours_match(
+    image: &DynamicImage,
+    board: [f32; 4],
+    light_square: [u8; 3],
+    dark_square: [u8; 3],
+) -> bool {
+    let Some(colours) = corner_colours(image, board) else {
+        return false;
+    };
+    colours
+        .iter()
+        .zip([light_square, dark_square])
+        .all(|(colour, expected)| {
+            colour
+                .iter()
+                .zip(expected)
+                .all(|(&channel, expected)| channel.abs_diff(expected) <= COLOUR_TOLERANCE)
+        })
+}
+
+/// The median colours of the light and dark squares, taken next to their top left corners where
+/// pieces rarely reach. The median leaves out the few highlighted or labelled squares.
+fn corner_colours(image: &DynamicImage, board: [f32; 4]) -> Option<[[u8; 3]; 2]> {
+    let image = image.to_rgb8();
+    let mut samples: [Vec<[u8; 3]>; 2] = [Vec::new(), Vec::new()];
+    for row in 0..8 {
+        for column in 0..8 {
+            let [x, y, width, height] = square_rect(board, row, column);
+            let (x, y) = (x + width / 8.0, y + height / 8.0);
+            if x < 0.0 || y < 0.0 || x as u32 >= image.width() || y as u32 >= image.height() {
+                return None;
+            }
+            // the top left square is always light
+            samples[(row + column) % 2].push(image.get_pixel(x as u32, y as u32).0);
+        }
+    }
+
+    Some(samples.map(|mut samples| {
+        [0, 1, 2].map(|channel| {
+            samples.sort_by_key(|sample| sample[channel]);
+            samples[samples.len() / 2][channel]
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use imageproc::image::{Rgb, RgbImage};
+
+    const LIGHT: [u8; 3] = [238, 238, 210];
+    const DARK: [u8; 3] = [118, 150, 86];
+
+    /// A 40 pixel board at (20, 20) with a piece-like blob in the middle of every square.
+    fn board_image(light: [u8; 3], dark: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(360, 360, |x, y| {
+            if !(20..340).contains(&x) || !(20..340).contains(&y) {
+                return Rgb([40, 40, 40]);
+            }
+            let (column, row) = ((x - 20) / 40, (y - 20) / 40);
+            if (10..30).contains(&((x - 20) % 40)) && (10..30).contains(&((y - 20) % 40)) {
+                return Rgb([0, 0, 0]);
+            }
+            Rgb(if (row + column) % 2 == 0 { light } else { dark })
+        }))
+    }
+
+    #[test]
+    fn matches_the_calibrated_squares() {
+        let image = board_image(LIGHT, DARK);
+        assert!(square_colours_match(
+            &image,
+            [20.0, 20.0, 320.0, 320.0],
+            LIGHT,
+            DARK
+        ));
+        // a pixel or two off is still the board
+        assert!(square_colours_match(
+            &image,
+            [22.0, 21.0, 320.0, 320.0],
+            LIGHT,
+            DARK
+        ));
+    }
+
+    #[test]
+    fn notices_a_moved_board_or_another_theme() {
+        let image = board_image(LIGHT, DARK);
+        assert!(!square_colours_match(
+            &image,
+            [60.0, 20.0, 320.0, 320.0],
+            LIGHT,
+            DARK
+        ));
+        assert!(!square_colours_match(
+            &image,
+            [200.0, 200.0, 320.0, 320.0],
+            LIGHT,
+            DARK
+        ));
+
+        let image = board_image([240, 217, 181], [181, 136, 99]);
+        assert!(!square_colours_match(
+            &image,
+            [20.0, 20.0, 320.0, 320.0],
+            LIGHT,
+            DARK
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    arg_parser::PromotionMode,
+    chess_detection::{ChessDetection, DetectionLevel},
+    input_capture::InputCaptureTrait,
+    model_info::PIECE_MAP,
+};
+use anyhow::{Context, Result};
+use ndarray::{ArrayBase, Axis, IxDyn, OwnedRepr};
+use std::io::{self, Read};
+
+/// Picks the promotion piece after a pawn reached the last rank.
+///
+/// `destination` is the screen position of the center of the promotion square and
+/// `promotion_piece` is the piece letter from the engine's move (q, r, b or n). Unless `may_ask`,
+/// a dialog that isn't found is reported instead of asking the user to promote.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_promotion(
+    promotion_mode: PromotionMode,
+    promotion_order: &str,
+    promotion_piece: char,
+    destination: (u32, u32),
+    tile_size: u32,
+    is_white_pov: bool,
+    dialog_delay: f32,
+    may_ask: bool,
+    detection_level: &DetectionLevel,
+    chess_detector: &ChessDetection,
+    input_capture: &mut Box<dyn InputCaptureTrait>,
+) -> Result<()> {
+    match promotion_mode {
+        PromotionMode::Manual => manual_promotion(promotion_piece),
+        PromotionMode::Offset => {
+            let offset = promotion_order.find(promotion_piece).context(format!(
+                "Piece `{}` is not part of the promotion order `{}`",
+                promotion_piece, promotion_order
+            ))? as u32;
+
+            // the promotion rank is always at the top of the screen from our point of view
+            input_capture.click_at(destination.0, destination.1 + offset * tile_size)
+        }
+        PromotionMode::Dialog => {
+            std::thread::sleep(std::time::Duration::from_secs_f32(dialog_delay));
+            let screenshot = input_capture.screenshot()?;
+            let detections = chess_detector
+                .detect(&screenshot, detection_level)
+                .context("Detection failed")?;
+
+            let piece = if is_white_pov {
+                promotion_piece.to_ascii_uppercase()
+            } else {
+                promotion_piece
+            };
+            let dialog_piece = detections.and_then(|detections| {
+                find_dialog_piece(
+                    &detections,
+                    piece,
+                    destination,
+                    tile_size,
+                    chess_detector.confidence_threshold(),
+                )
+            });
+
+            match dialog_piece {
+                Some((x, y)) => input_capture.click_at(x, y),
+                None if may_ask => manual_promotion(promotion_piece),
+                None => {
+                    println!("The promotion dialog wasn't found.");
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+fn manual_promotion(promotion_piece: char) -> Result<()> {
+    println!(
+        "Promotion move detected. Please manually promote to {} and press enter...",
+        promotion_piece
+    );
+    io::stdin().read_exact(&mut [0])?;
+    Ok(())
+}
+
+/// Looks for `piece` in the promotion file, within the four squares a promotion dialog
+/// covers starting at the promotion square, and returns the center of the closest match.
+fn find_dialog_piece(
+    detections: &ArrayBase<OwnedRepr<f32>, IxDyn>,
+    piece: char,
+    destination: (u32, u32),
+    tile_size: u32,
+    confidence_threshold: f32,
+) -> Option<(u32, u32)> {
+    let class_id = PIECE_MAP.iter().position(|&p| p == piece)? as f32;
+    let half_tile = tile_size as f32 / 2.0;
+    let (dest_x, dest_y) = (destination.0 as f32, destination.1 as f32);
+
+    detections
+        .axis_iter(Axis(0))
+        .filter(|row| row[4] >= confidence_threshold && row[5] == class_id)
+        .map(|row| (row[0] + row[2] / 2.0, row[1] + row[3] / 2.0))
+        .filter(|&(x, y)| {
+            (x - dest_x).abs() <= half_tile
+                && y >= dest_y - half_tile
+                && y <= dest_y + 3.0 * tile_size as f32 + half_tile
+        })
+        .min_by(|a, b| (a.1 - dest_y).abs().total_cmp(&(b.1 - dest_y).abs()))
+        .map(|(x, y)| (x as u32, y as u32))
+}
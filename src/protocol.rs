@@ -0,0 +1,498 @@
+// Framing of the images `process -` reads from stdin.
+//
+// Legacy format: POV byte (1 = white), image size as native-endian u32, encoded image.
+//
+// Version 1 (all integers little-endian):
+//   magic "CHST", version u8 (1), pixel format u8, POV u8, flags u8 (reserved, 0)
+//   width u32, height u32, stride u32 (bytes per row, 0 = tightly packed)
+//   metadata size u32, payload size u32
+//   metadata (JSON object, may be empty), payload (pixels, or an encoded image)
+//
+// The first byte tells them apart: the legacy POV byte is 0 or 1, version 1 starts with 'C'.
+//
+// With `--framed`, every frame is answered with (integers little-endian):
+//   magic "CHST", version u8 (1), header size u32, payload size u32
+//   header (JSON object, see `Response`), payload (annotated PNG, may be empty)
+
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{self, DynamicImage, ImageBuffer, Rgb, Rgba};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"CHST";
+pub const VERSION: u8 = 1;
+
+// larger sizes in a header are a broken frame, not something to allocate
+const MAX_METADATA_SIZE: u32 = 1 << 20;
+// an 8K RGBA screenshot is about 130 MiB
+const MAX_PAYLOAD_SIZE: u32 = 256 << 20;
+
+/// Layout of the payload of a version 1 frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    /// PNG, JPEG or anything else the "image" crate can decode.
+    Encoded,
+    Rgb8,
+    Rgba8,
+    Bgra8,
+}
+
+impl PixelFormat {
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Self::Encoded),
+            1 => Ok(Self::Rgb8),
+            2 => Ok(Self::Rgba8),
+            3 => Ok(Self::Bgra8),
+            _ => Err(anyhow!("Unknown pixel format {}", byte)),
+        }
+    }
+
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Encoded => 0,
+            Self::Rgb8 => 3,
+            Self::Rgba8 | Self::Bgra8 => 4,
+        }
+    }
+}
+
+/// Optional per-frame metadata, overrides the command line options for this frame.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FrameMetadata {
+    /// Echoed back with the results so they can be matched to the frame.
+    pub frame_id: Option<u64>,
+    pub no_fen: Option<bool>,
+    pub print_detections: Option<bool>,
+    pub best_chessboard_detection_only: Option<bool>,
+    pub refined_search: Option<bool>,
+}
+
+pub struct Frame {
+    /// None when the frame leaves the POV to the command line.
+    pub is_white_pov: Option<bool>,
+    pub image: DynamicImage,
+    pub metadata: FrameMetadata,
+}
+
+/// A frame that was read completely but not decoded yet. Decoding errors don't desynchronize the stream.
+pub struct RawFrame {
+    is_white_pov: Option<bool>,
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: u32,
+    metadata: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn decode(self) -> Result<Frame> {
+        let metadata = if self.metadata.is_empty() {
+            FrameMetadata::default()
+        } else {
+            serde_json::from_slice(&self.metadata).context("Invalid frame metadata")?
+        };
+
+        let image = if self.pixel_format == PixelFormat::Encoded {
+            image::load_from_memory(&self.payload).context("Failed to load image from memory")?
+        } else {
+            raw_to_image(
+                &self.payload,
+                self.pixel_format,
+                self.width,
+                self.height,
+                self.stride,
+            )?
+        };
+
+        Ok(Frame {
+            is_white_pov: self.is_white_pov,
+            image,
+            metadata,
+        })
+    }
+}
+
+/// Reads one frame in either format.
+pub fn read_frame(handle: &mut impl Read) -> Result<Frame> {
+    read_raw_frame(handle)?
+        .context("Reading POV failed")?
+        .decode()
+}
+
+/// Reads the bytes of one frame in either format, None if the input ended before it.
+pub fn read_raw_frame(handle: &mut impl Read) -> Result<Option<RawFrame>> {
+    let mut first_byte = [0u8; 1];
+    if handle.read(&mut first_byte).context("Reading POV failed")? == 0 {
+        return Ok(None);
+    }
+
+    if first_byte[0] == MAGIC[0] {
+        read_frame_v1(handle).map(Some)
+    } else {
+        read_legacy_frame(first_byte[0] == 1, handle).map(Some)
+    }
+}
+
+fn read_legacy_frame(is_white_pov: bool, handle: &mut impl Read) -> Result<RawFrame> {
+    let image_size = u32::from_ne_bytes(read_array(handle).context("Reading image size failed")?);
+    check_size("Image", image_size, MAX_PAYLOAD_SIZE)?;
+
+    let mut image_data = vec![0u8; image_size as usize];
+    handle
+        .read_exact(&mut image_data)
+        .context("Reading image data failed")?;
+
+    Ok(RawFrame {
+        is_white_pov: Some(is_white_pov),
+        pixel_format: PixelFormat::Encoded,
+        width: 0,
+        height: 0,
+        stride: 0,
+        metadata: Vec::new(),
+        payload: image_data,
+    })
+}
+
+fn read_frame_v1(handle: &mut impl Read) -> Result<RawFrame> {
+    // the first magic byte was already read
+    let header: [u8; 7] = read_array(handle).context("Reading header failed")?;
+    if header[..3] != MAGIC[1..] {
+        return Err(anyhow!("Invalid frame header"));
+    }
+    if header[3] != VERSION {
+        return Err(anyhow!("Unsupported protocol version {}", header[3]));
+    }
+    let pixel_format = PixelFormat::from_byte(header[4])?;
+    let is_white_pov = match header[5] {
+        0 => Some(false),
+        1 => Some(true),
+        2 => None,
+        pov => return Err(anyhow!("Invalid POV {}", pov)),
+    };
+    if header[6] != 0 {
+        return Err(anyhow!("Unknown frame flags {:#04x}", header[6]));
+    }
+
+    let width = read_u32(handle).context("Reading width failed")?;
+    let height = read_u32(handle).context("Reading height failed")?;
+    let stride = read_u32(handle).context("Reading stride failed")?;
+    let metadata_size = read_u32(handle).context("Reading metadata size failed")?;
+    let payload_size = read_u32(handle).context("Reading payload size failed")?;
+    check_size("Metadata", metadata_size, MAX_METADATA_SIZE)?;
+    check_size("Payload", payload_size, MAX_PAYLOAD_SIZE)?;
+
+    let mut metadata = vec![0u8; metadata_size as usize];
+    handle
+        .read_exact(&mut metadata)
+        .context("Reading metadata failed")?;
+
+    let mut payload = vec![0u8; payload_size as usize];
+    handle
+        .read_exact(&mut payload)
+        .context("Reading image data failed")?;
+
+    Ok(RawFrame {
+        is_white_pov,
+        pixel_format,
+        width,
+        height,
+        stride,
+        metadata,
+        payload,
+    })
+}
+
+fn check_size(what: &str, size: u32, max_size: u32) -> Result<()> {
+    if size > max_size {
+        return Err(anyhow!(
+            "{} size {} is larger than the maximum of {}",
+            what,
+            size,
+            max_size
+        ));
+    }
+    Ok(())
+}
+
+/// One detection as reported in responses.
+#[derive(Debug, Serialize)]
+pub struct Detection {
+    pub class: u32,
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub confidence: f32,
+}
+
+impl Detection {
+    pub fn from_row(row: &[f32]) -> Self {
+        Self {
+            class: row[5] as u32,
+            x: row[0],
+            y: row[1],
+            width: row[2],
+            height: row[3],
+            confidence: row[4],
+        }
+    }
+}
+
+/// Header of a response frame.
+#[derive(Debug, Default, Serialize)]
+pub struct Response {
+    /// The `frame_id` from the frame's metadata.
+    pub frame_id: Option<u64>,
+    /// Counts the frames read, starting at 0, so legacy frames can be matched too.
+    pub sequence: u64,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fen: Option<String>,
+    /// How sure the FEN is, from 0 to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detections: Option<Vec<Detection>>,
+    /// "png" when the payload holds the annotated image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_format: Option<&'static str>,
+}
+
+pub fn write_response(out: &mut impl Write, response: &Response, payload: &[u8]) -> Result<()> {
+    let header = serde_json::to_vec(response)?;
+
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    out.write_all(&(header.len() as u32).to_le_bytes())?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(&header)?;
+    out.write_all(payload)?;
+    out.flush().context("Failed to write response")
+}
+
+fn raw_to_image(
+    payload: &[u8],
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+    stride: u32,
+) -> Result<DynamicImage> {
+    let row_size = width as usize * pixel_format.bytes_per_pixel();
+    let stride = if stride == 0 {
+        row_size
+    } else {
+        stride as usize
+    };
+    if stride < row_size {
+        return Err(anyhow!("Stride {} is smaller than a row", stride));
+    }
+    // the last row doesn't need the padding
+    let needed = stride * (height as usize).saturating_sub(1) + row_size;
+    if height == 0 || width == 0 || payload.len() < needed {
+        return Err(anyhow!(
+            "Payload of {} bytes is too small for a {}x{} image",
+            payload.len(),
+            width,
+            height
+        ));
+    }
+
+    let mut pixels = Vec::with_capacity(row_size * height as usize);
+    for row in payload.chunks(stride).take(height as usize) {
+        let row = &row[..row_size];
+        if pixel_format == PixelFormat::Bgra8 {
+            for chunk in row.chunks_exact(4) {
+                pixels.extend_from_slice(&[chunk[2], chunk[1], chunk[0], chunk[3]]);
+            }
+        } else {
+            pixels.extend_from_slice(row);
+        }
+    }
+
+    let image = match pixel_format {
+        PixelFormat::Rgb8 => {
+            ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        _ => ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, pixels)
+            .map(DynamicImage::ImageRgba8),
+    };
+    image.context("Failed to create image from raw pixels")
+}
+
+fn read_u32(handle: &mut impl Read) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_array(handle)?))
+}
+
+fn read_array<const N: usize>(handle: &mut impl Read) -> Result<[u8; N]> {
+    let mut buffer = [0u8; N];
+    handle.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_v1(header: [u8; 4], sizes: [u32; 5], metadata: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut frame = MAGIC.to_vec();
+        frame.extend_from_slice(&header);
+        for size in sizes {
+            frame.extend_from_slice(&size.to_le_bytes());
+        }
+        frame.extend_from_slice(metadata);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn read(frame: &[u8]) -> Result<Option<RawFrame>> {
+        read_raw_frame(&mut &frame[..])
+    }
+
+    #[test]
+    fn reads_raw_pixels() {
+        let metadata = br#"{"frame_id": 7}"#;
+        // 2x1 BGRA with a row padded to 12 bytes
+        let payload = [3, 2, 1, 255, 6, 5, 4, 255, 0, 0, 0, 0];
+        let frame = frame_v1(
+            [VERSION, 3, 1, 0],
+            [2, 1, 12, metadata.len() as u32, payload.len() as u32],
+            metadata,
+            &payload,
+        );
+
+        let frame = read(&frame).unwrap().unwrap().decode().unwrap();
+        assert_eq!(frame.is_white_pov, Some(true));
+        assert_eq!(frame.metadata.frame_id, Some(7));
+        assert_eq!(
+            frame.image.into_rgba8().into_raw(),
+            [1, 2, 3, 255, 4, 5, 6, 255]
+        );
+    }
+
+    #[test]
+    fn reads_legacy_frames() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(3, 2)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let mut frame = vec![0];
+        frame.extend_from_slice(&(png.len() as u32).to_ne_bytes());
+        frame.extend_from_slice(&png);
+
+        let frame = read(&frame).unwrap().unwrap().decode().unwrap();
+        assert_eq!(frame.is_white_pov, Some(false));
+        assert_eq!((frame.image.width(), frame.image.height()), (3, 2));
+    }
+
+    #[test]
+    fn ends_cleanly_between_frames() {
+        assert!(read(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let sizes = [1, 1, 0, 0, 3];
+        let payload = [0; 3];
+        for (header, error) in [
+            ([VERSION, 1, 1, 0x01], "Unknown frame flags 0x01"),
+            ([VERSION, 1, 1, 0x80], "Unknown frame flags 0x80"),
+            ([2, 1, 1, 0], "Unsupported protocol version 2"),
+            ([VERSION, 9, 1, 0], "Unknown pixel format 9"),
+            ([VERSION, 1, 3, 0], "Invalid POV 3"),
+        ] {
+            let frame = frame_v1(header, sizes, &[], &payload);
+            let err = read(&frame).err().expect(error);
+            assert_eq!(err.to_string(), error);
+        }
+
+        let mut frame = frame_v1([VERSION, 1, 1, 0], sizes, &[], &payload);
+        frame[2] = b'X';
+        assert_eq!(
+            read(&frame).err().unwrap().to_string(),
+            "Invalid frame header"
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_frames_before_reading_them() {
+        // only the header is there, the sizes alone have to be refused
+        let frame = frame_v1(
+            [VERSION, 1, 1, 0],
+            [1, 1, 0, MAX_METADATA_SIZE + 1, 3],
+            &[],
+            &[],
+        );
+        assert!(read(&frame)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Metadata size"));
+
+        let frame = frame_v1([VERSION, 1, 1, 0], [1, 1, 0, 0, u32::MAX], &[], &[]);
+        assert!(read(&frame)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Payload size"));
+
+        let mut frame = vec![1];
+        frame.extend_from_slice(&(MAX_PAYLOAD_SIZE + 1).to_ne_bytes());
+        assert!(read(&frame)
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("Image size"));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let frame = frame_v1([VERSION, 1, 1, 0], [1, 1, 0, 0, 3], &[], &[0; 3]);
+        for end in [3, 8, 20, frame.len() - 1] {
+            assert!(read(&frame[..end]).is_err(), "{} bytes", end);
+        }
+    }
+
+    #[test]
+    fn rejects_payloads_that_dont_fit_the_size() {
+        assert!(raw_to_image(&[0; 5], PixelFormat::Rgb8, 2, 1, 0).is_err());
+        assert!(raw_to_image(&[0; 6], PixelFormat::Rgb8, 2, 1, 4).is_err());
+        assert!(raw_to_image(&[0; 6], PixelFormat::Rgb8, 0, 1, 0).is_err());
+        // the last row needs no padding
+        assert!(raw_to_image(&[0; 14], PixelFormat::Rgb8, 2, 2, 8).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_metadata() {
+        let metadata = br#"{"frame": 7}"#;
+        let frame = frame_v1(
+            [VERSION, 1, 1, 0],
+            [1, 1, 0, metadata.len() as u32, 3],
+            metadata,
+            &[0; 3],
+        );
+        assert!(read(&frame).unwrap().unwrap().decode().is_err());
+    }
+
+    #[test]
+    fn writes_responses() {
+        let response = Response {
+            sequence: 3,
+            ok: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_response(&mut out, &response, &[9, 9]).unwrap();
+
+        let header = br#"{"frame_id":null,"sequence":3,"ok":true}"#;
+        assert_eq!(&out[..5], b"CHST\x01");
+        assert_eq!(out[5..9], (header.len() as u32).to_le_bytes());
+        assert_eq!(out[9..13], 2u32.to_le_bytes());
+        assert_eq!(&out[13..13 + header.len()], header);
+        assert_eq!(&out[13 + header.len()..], [9, 9]);
+    }
+}
@@ -0,0 +1,248 @@
+// Reading the position square by square once the board has been found. The detection model,
+// a square classifier or both can be used, their probabilities are averaged.
+
+use crate::model_info::PIECE_MAP;
+use anyhow::Result;
+use imageproc::image::DynamicImage;
+
+/// Classes a square can have: the pieces in `PIECE_MAP` order, then empty.
+pub const CLASS_COUNT: usize = 13;
+pub const EMPTY: usize = 12;
+
+// how confident an empty square with suspicious pixels counts for the board's confidence
+const SUSPICIOUS_CONFIDENCE: f32 = 0.25;
+// squares read with a lower confidence are listed under the uncertainty grid
+const UNCERTAIN_CONFIDENCE: f32 = 0.9;
+
+/// Probability of every class on every square, in screen order (top row first, left to right).
+#[derive(Debug, Clone)]
+pub struct BoardProbabilities {
+    pub squares: [[[f32; CLASS_COUNT]; 8]; 8],
+}
+
+/// What was read on a square.
+#[derive(Debug, Clone, Copy)]
+pub struct SquareReading {
+    pub class: usize,
+    pub confidence: f32,
+    /// The next most likely class and its probability.
+    pub runner_up: (usize, f32),
+    /// Read as empty but its pixels aren't plain, e.g. a piece that was missed.
+    pub suspicious: bool,
+}
+
+/// The position read on a board, with how sure every square is.
+#[derive(Debug, Clone)]
+pub struct BoardReading {
+    pub fen: String,
+    /// In FEN order (rank 8 first, file a first).
+    pub squares: [[SquareReading; 8]; 8],
+    /// The confidence of the least certain square, suspicious squares counting as barely certain.
+    pub confidence: f32,
+}
+
+pub trait Recognizer {
+    /// Classifies the squares of the board at `board` (x, y, width, height) in `image`.
+    fn recognize(&self, image: &DynamicImage, board: [f32; 4]) -> Result<BoardProbabilities>;
+}
+
+impl BoardProbabilities {
+    /// Every square empty.
+    pub fn empty() -> Self {
+        let mut square = [0.0; CLASS_COUNT];
+        square[EMPTY] = 1.0;
+        Self {
+            squares: [[square; 8]; 8],
+        }
+    }
+
+    /// Averages the readings of several recognizers, each square's probabilities summing to 1.
+    pub fn combine(readings: &[BoardProbabilities]) -> Self {
+        let mut combined = Self {
+            squares: [[[0.0; CLASS_COUNT]; 8]; 8],
+        };
+        for reading in readings {
+            for (combined_row, row) in combined.squares.iter_mut().zip(&reading.squares) {
+                for (combined_square, square) in combined_row.iter_mut().zip(row) {
+                    let total: f32 = square.iter().sum();
+                    if total <= 0.0 {
+                        continue;
+                    }
+                    for (combined, probability) in combined_square.iter_mut().zip(square) {
+                        *combined += probability / total / readings.len() as f32;
+                    }
+                }
+            }
+        }
+        combined
+    }
+
+    /// The most likely class of a square, pieces winning ties with empty.
+    pub fn best_class(&self, row: usize, column: usize) -> usize {
+        self.ranked_classes(row, column)[0]
+    }
+
+    /// The classes of a square from the most to the least likely.
+    fn ranked_classes(&self, row: usize, column: usize) -> [usize; CLASS_COUNT] {
+        let square = &self.squares[row][column];
+        let mut classes: [usize; CLASS_COUNT] = std::array::from_fn(|class| class);
+        // stable, so equally likely classes stay in class order
+        classes.sort_by(|&a, &b| square[b].total_cmp(&square[a]));
+        classes
+    }
+
+    /// The most likely classes of a square and how likely they are, `suspicious` when it
+    /// would be read as empty but doesn't look empty.
+    pub fn square_reading(&self, row: usize, column: usize, suspicious: bool) -> SquareReading {
+        let square = &self.squares[row][column];
+        let total: f32 = square.iter().sum::<f32>().max(f32::EPSILON);
+        let [class, runner_up, ..] = self.ranked_classes(row, column);
+        SquareReading {
+            class,
+            confidence: square[class] / total,
+            runner_up: (runner_up, square[runner_up] / total),
+            suspicious: suspicious && class == EMPTY,
+        }
+    }
+
+    /// FEN piece placement of the most likely classes, `white_pov` when white is at the bottom.
+    pub fn to_fen(&self, white_pov: bool) -> String {
+        let mut board = [[' '; 8]; 8];
+        for (row, squares) in board.iter_mut().enumerate() {
+            for (column, square) in squares.iter_mut().enumerate() {
+                let (screen_row, screen_column) = if white_pov {
+                    (row, column)
+                } else {
+                    (7 - row, 7 - column)
+                };
+                if let Some(&piece) = PIECE_MAP.get(self.best_class(screen_row, screen_column)) {
+                    *square = piece;
+                }
+            }
+        }
+        board_to_fen(&board)
+    }
+}
+
+impl BoardReading {
+    /// Reads the board, `suspicious` telling which squares in screen order don't look empty.
+    pub fn new(
+        probabilities: &BoardProbabilities,
+        suspicious: &[[bool; 8]; 8],
+        white_pov: bool,
+    ) -> Self {
+        let squares = std::array::from_fn(|rank| {
+            std::array::from_fn(|file| {
+                let (row, column) = if white_pov {
+                    (rank, file)
+                } else {
+                    (7 - rank, 7 - file)
+                };
+                probabilities.square_reading(row, column, suspicious[row][column])
+            })
+        });
+        let confidence = squares
+            .iter()
+            .flatten()
+            .map(|square: &SquareReading| {
+                if square.suspicious {
+                    square.confidence.min(SUSPICIOUS_CONFIDENCE)
+                } else {
+                    square.confidence
+                }
+            })
+            .fold(1.0, f32::min);
+
+        Self {
+            fen: probabilities.to_fen(white_pov),
+            squares,
+            confidence,
+        }
+    }
+
+    /// The board with the piece and confidence of every square ('.' for empty, '?' after
+    /// suspicious squares), followed by the uncertain squares and what else they could be.
+    pub fn uncertainty_grid(&self) -> String {
+        let mut grid = format!("Board confidence: {:.2}\n", self.confidence);
+        for (rank, squares) in self.squares.iter().enumerate() {
+            grid.push_str(&format!("{} ", 8 - rank));
+            for square in squares {
+                grid.push_str(&format!(
+                    " {}{} {:.2}",
+                    class_char(square.class),
+                    if square.suspicious { '?' } else { ' ' },
+                    square.confidence
+                ));
+            }
+            grid.push('\n');
+        }
+        let files: Vec<String> = ('a'..='h').map(|file| format!("{:<7}", file)).collect();
+        grid.push_str(&format!("   {}\n", files.join(" ").trim_end()));
+
+        for (rank, squares) in self.squares.iter().enumerate() {
+            for (file, square) in squares.iter().enumerate() {
+                let name = format!("{}{}", (b'a' + file as u8) as char, 8 - rank);
+                if square.suspicious {
+                    grid.push_str(&format!("{}: read as empty but doesn't look empty\n", name));
+                } else if square.confidence < UNCERTAIN_CONFIDENCE {
+                    grid.push_str(&format!(
+                        "{}: {} {:.2}, or {} {:.2}\n",
+                        name,
+                        class_name(square.class),
+                        square.confidence,
+                        class_name(square.runner_up.0),
+                        square.runner_up.1
+                    ));
+                }
+            }
+        }
+        grid
+    }
+}
+
+fn class_char(class: usize) -> char {
+    PIECE_MAP.get(class).copied().unwrap_or('.')
+}
+
+fn class_name(class: usize) -> String {
+    match PIECE_MAP.get(class) {
+        Some(piece) => piece.to_string(),
+        None => "empty".to_string(),
+    }
+}
+
+/// FEN piece placement of a board of pieces (' ' for empty squares), rank 8 first.
+pub fn board_to_fen(board: &[[char; 8]; 8]) -> String {
+    let mut fen = String::with_capacity(64 + 7); // 64 for the board, 7 for the slashes
+    for row in board {
+        let mut empty_count: u8 = 0;
+        for &cell in row.iter() {
+            if cell == ' ' {
+                empty_count += 1;
+            } else {
+                if empty_count > 0 {
+                    fen.push((b'0' + empty_count) as char);
+                    empty_count = 0;
+                }
+                fen.push(cell);
+            }
+        }
+        if empty_count > 0 {
+            fen.push((b'0' + empty_count) as char);
+        }
+        fen.push('/');
+    }
+    fen.pop(); // Remove the last '/'
+    fen
+}
+
+/// The square (x, y, width, height) at `row` and `column` of the board at `board`, in screen order.
+pub fn square_rect(board: [f32; 4], row: usize, column: usize) -> [f32; 4] {
+    let (width, height) = (board[2] / 8.0, board[3] / 8.0);
+    [
+        board[0] + column as f32 * width,
+        board[1] + row as f32 * height,
+        width,
+        height,
+    ]
+}
@@ -0,0 +1,144 @@
+use crate::drawing::annotate_detections;
+use anyhow::{Context, Result};
+use imageproc::image::DynamicImage;
+use ndarray::{ArrayBase, IxDyn, OwnedRepr};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Saves captured frames, their annotated detections, the extracted FEN and the engine's
+/// replies into a timestamped session folder, so misreads can be inspected afterwards.
+///
+/// Every entry is appended to `index.tsv` in the session folder with the columns:
+/// `kind`, `time_ms`, `frame`, `fen`, `detail`.
+pub struct SessionRecorder {
+    session_dir: PathBuf,
+    index: File,
+    frame_count: u32,
+    changed_frames_only: bool,
+    last_fen: Option<String>,
+    confidence_threshold: f32,
+    labels: Vec<String>,
+}
+
+impl SessionRecorder {
+    pub fn new(
+        record_dir: &str,
+        changed_frames_only: bool,
+        confidence_threshold: f32,
+        labels: Vec<String>,
+    ) -> Result<Self> {
+        let session_dir = create_session_dir(record_dir)?;
+
+        let mut index = File::create_new(session_dir.join("index.tsv"))
+            .context("Failed to create the session index file")?;
+        writeln!(index, "kind\ttime_ms\tframe\tfen\tdetail")?;
+
+        println!("Recording session to {}", session_dir.display());
+
+        Ok(Self {
+            session_dir,
+            index,
+            frame_count: 0,
+            changed_frames_only,
+            last_fen: None,
+            confidence_threshold,
+            labels,
+        })
+    }
+
+    /// Records a captured frame with its detections and extracted FEN.
+    /// `fen` is `None` when the board could not be read from the frame.
+    pub fn record_frame(
+        &mut self,
+        frame: &DynamicImage,
+        detections: Option<&ArrayBase<OwnedRepr<f32>, IxDyn>>,
+        fen: Option<&str>,
+    ) -> Result<()> {
+        if self.changed_frames_only && fen.is_some() && self.last_fen.as_deref() == fen {
+            return Ok(());
+        }
+        self.last_fen = fen.map(|f| f.to_string());
+        self.frame_count += 1;
+
+        let frame_name = format!("frame_{:05}.png", self.frame_count);
+        frame
+            .save(self.session_dir.join(&frame_name))
+            .context(format!("Failed to save {}", frame_name))?;
+
+        let mut detail = String::from("-");
+        if let Some(detections) = detections {
+            let annotated_name = format!("frame_{:05}_annotated.png", self.frame_count);
+            let confidence_threshold = self.confidence_threshold;
+            let mut annotated = frame.clone();
+            annotate_detections(
+                &mut annotated,
+                detections,
+                &self.labels,
+                &|row: &[f32]| row[4] >= confidence_threshold,
+            );
+            annotated
+                .save(self.session_dir.join(&annotated_name))
+                .context(format!("Failed to save {}", annotated_name))?;
+            detail = annotated_name;
+        }
+
+        let frame_id = self.frame_count.to_string();
+        self.write_entry("frame", &frame_id, fen.unwrap_or("-"), &detail)
+    }
+
+    /// Records the engine's reply for the given position.
+    pub fn record_engine_reply(&mut self, fen: &str, best_move: &str) -> Result<()> {
+        let frame_id = self.frame_count.to_string();
+        self.write_entry("move", &frame_id, fen, best_move)
+    }
+
+    fn write_entry(&mut self, kind: &str, frame: &str, fen: &str, detail: &str) -> Result<()> {
+        writeln!(
+            self.index,
+            "{}\t{}\t{}\t{}\t{}",
+            kind,
+            now_ms(),
+            frame,
+            fen,
+            detail
+        )
+        .context("Failed to write to the session index file")?;
+        self.index.flush()?;
+        Ok(())
+    }
+}
+
+/// Creates a new `session-<time_ms>` folder in `record_dir`, with a `-<n>` suffix when a
+/// session started in the same millisecond already has it.
+fn create_session_dir(record_dir: &str) -> Result<PathBuf> {
+    fs::create_dir_all(record_dir)
+        .context(format!("Failed to create record folder {}", record_dir))?;
+
+    let name = format!("session-{}", now_ms());
+    let mut session_dir = PathBuf::from(record_dir).join(&name);
+    let mut suffix = 1;
+    loop {
+        match fs::create_dir(&session_dir) {
+            Ok(()) => return Ok(session_dir),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                session_dir = PathBuf::from(record_dir).join(format!("{}-{}", name, suffix));
+                suffix += 1;
+            }
+            Err(err) => {
+                return Err(err).context(format!(
+                    "Failed to create session folder {}",
+                    session_dir.display()
+                ))
+            }
+        }
+    }
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
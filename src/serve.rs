@@ -0,0 +1,220 @@
+use crate::arg_parser::{Args, Pov};
+use crate::chess_detection::{ChessDetection, DetectionLevel};
+use crate::drawing::annotate_detections;
+use crate::process::process_detections_and_generate_filter;
+use crate::protocol::Detection;
+use anyhow::{anyhow, Result};
+use imageproc::image::{self, ImageFormat};
+use serde_json::{json, Value};
+use std::io::{Cursor, Read};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Serves detection over HTTP, one worker thread per detector so at most that many requests run inference at once.
+///
+/// Endpoints:
+///   GET  /health    model metadata
+///   POST /detect    image in the body, returns the FEN and detections as JSON
+///   POST /annotate  image in the body, returns the annotated PNG (FEN in the X-Chust-Fen header)
+/// Both POST endpoints take the query options `pov=w|b`, `refined=true|false` and `best_chessboard_only=true|false`.
+pub fn serve(
+    listen: &str,
+    max_body_size: usize,
+    args: &Args,
+    chess_detectors: Vec<ChessDetection>,
+) -> Result<()> {
+    let server =
+        Server::http(listen).map_err(|err| anyhow!("Failed to listen on {}: {}", listen, err))?;
+    let health = health(args, &chess_detectors);
+    println!(
+        "Listening on http://{} with {} session(s)",
+        listen,
+        chess_detectors.len()
+    );
+
+    thread::scope(|scope| {
+        for chess_detector in &chess_detectors {
+            let (server, health) = (&server, &health);
+            scope.spawn(move || {
+                for request in server.incoming_requests() {
+                    let response = handle(request, max_body_size, args, chess_detector, health);
+                    if let Err(err) = response {
+                        eprintln!("Failed to answer request: {}", err);
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(
+    mut request: Request,
+    max_body_size: usize,
+    args: &Args,
+    chess_detector: &ChessDetection,
+    health: &Value,
+) -> std::io::Result<()> {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    match (request.method(), path.as_str()) {
+        (Method::Get, "/health") => request.respond(json_response(200, health)),
+        (Method::Post, "/detect") | (Method::Post, "/annotate") => {
+            let mut body = Vec::new();
+            request
+                .as_reader()
+                .take(max_body_size as u64 + 1)
+                .read_to_end(&mut body)?;
+            if body.len() > max_body_size {
+                return request.respond(error_response(413, "Image too large"));
+            }
+
+            match detect(&body, &query, path == "/annotate", args, chess_detector) {
+                Ok(DetectResult::Json(value)) => request.respond(json_response(200, &value)),
+                Ok(DetectResult::Png(png, fen)) => {
+                    let mut response =
+                        Response::from_data(png).with_header(header("Content-Type", "image/png"));
+                    if let Some(fen) = fen {
+                        response = response.with_header(header("X-Chust-Fen", &fen));
+                    }
+                    request.respond(response)
+                }
+                Err((status, message)) => request.respond(error_response(status, &message)),
+            }
+        }
+        _ => request.respond(error_response(404, "Not found")),
+    }
+}
+
+enum DetectResult {
+    Json(Value),
+    Png(Vec<u8>, Option<String>),
+}
+
+fn detect(
+    body: &[u8],
+    query: &str,
+    annotate: bool,
+    args: &Args,
+    chess_detector: &ChessDetection,
+) -> std::result::Result<DetectResult, (u16, String)> {
+    let mut is_white_pov = args.pov == Pov::W;
+    let mut refined_search = args.refined_search;
+    let mut best_chessboard_detection_only = false;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match (key, value) {
+            ("pov", "w") => is_white_pov = true,
+            ("pov", "b") => is_white_pov = false,
+            ("refined", value) => refined_search = value == "true",
+            ("best_chessboard_only", value) => best_chessboard_detection_only = value == "true",
+            _ => return Err((400, format!("Invalid option {}={}", key, value))),
+        }
+    }
+
+    let mut image = image::load_from_memory(body)
+        .map_err(|err| (400, format!("Failed to load image: {}", err)))?;
+    let detection_level = if refined_search {
+        DetectionLevel::Refined
+    } else {
+        DetectionLevel::Basic
+    };
+
+    let detections = chess_detector
+        .detect(&image, &detection_level)
+        .map_err(|err| (500, format!("Detection failed: {}", err)))?
+        .ok_or((422, "Failed to find the chessboard".to_string()))?;
+
+    let (detection_filter, reading) = process_detections_and_generate_filter(
+        chess_detector,
+        args,
+        &image,
+        &detections,
+        is_white_pov,
+        false,
+        best_chessboard_detection_only,
+    )
+    .map_err(|err| (422, err.to_string()))?;
+    let confidence = reading.as_ref().map(|reading| reading.confidence);
+    let fen = reading.map(|reading| reading.fen);
+
+    if annotate {
+        annotate_detections(
+            &mut image,
+            &detections,
+            chess_detector.labels(),
+            &detection_filter,
+        );
+        let mut png = Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, ImageFormat::Png)
+            .map_err(|err| (500, format!("Failed to encode image: {}", err)))?;
+        return Ok(DetectResult::Png(png.into_inner(), fen));
+    }
+
+    let detections: Vec<Detection> = detections
+        .axis_iter(ndarray::Axis(0))
+        .filter_map(|row| {
+            let row = row.to_slice()?;
+            detection_filter(row).then(|| Detection::from_row(row))
+        })
+        .collect();
+
+    Ok(DetectResult::Json(json!({
+        "fen": fen,
+        "confidence": confidence,
+        "detections": detections,
+    })))
+}
+
+fn health(args: &Args, chess_detectors: &[ChessDetection]) -> Value {
+    let model = match (
+        chess_detectors[0].session(),
+        chess_detectors[0].model_info(),
+    ) {
+        (Some(session), Some(model_info)) => {
+            let metadata = session.metadata().ok();
+            json!({
+                "path": if cfg!(feature = "embed_model") { "embedded" } else { args.model_path.as_str() },
+                "name": metadata.as_ref().and_then(|metadata| metadata.name().ok()),
+                "producer": metadata.as_ref().and_then(|metadata| metadata.producer().ok()),
+                "version": metadata.as_ref().and_then(|metadata| metadata.version().ok()),
+                "description": metadata.as_ref().and_then(|metadata| metadata.description().ok()),
+                "input_size": [model_info.input_size.0, model_info.input_size.1],
+                "labels": model_info.labels,
+                "inputs": session.inputs.iter().map(|input| json!({
+                    "name": input.name,
+                    "type": input.input_type.to_string(),
+                })).collect::<Vec<_>>(),
+                "outputs": session.outputs.iter().map(|output| json!({
+                    "name": output.name,
+                    "type": output.output_type.to_string(),
+                })).collect::<Vec<_>>(),
+            })
+        }
+        // the board is read without a detection model
+        _ => Value::Null,
+    };
+
+    json!({
+        "status": "ok",
+        "sessions": chess_detectors.len(),
+        "confidence_threshold": args.conf,
+        "model": model,
+    })
+}
+
+fn json_response(status: u16, value: &Value) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
+
+fn error_response(status: u16, message: &str) -> Response<Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
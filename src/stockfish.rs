@@ -3,7 +3,6 @@ use std::io::{BufRead, BufReader, Write};
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 
 pub struct Stockfish {
-    path: String,
     process: Child,
     stdin: ChildStdin,
     stdout: BufReader<ChildStdout>,
@@ -23,7 +22,6 @@ impl Stockfish {
         let stdout = BufReader::new(process.stdout.take().context("Failed to open stdout")?);
 
         Ok(Self {
-            path: path.to_string(),
             process,
             stdin,
             stdout,
@@ -80,3 +78,11 @@ impl Stockfish {
         Err(anyhow!("Stockfish did not return a valid FEN"))
     }
 }
+
+impl Drop for Stockfish {
+    /// Asks the engine to quit and reaps the process.
+    fn drop(&mut self) {
+        let _ = self.send_command("quit");
+        let _ = self.process.wait();
+    }
+}
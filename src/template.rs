@@ -0,0 +1,250 @@
+// Reads the board by comparing every square with images of the pieces captured from a screenshot
+// of the starting position (`chust calibrate`). No model is needed, which suits the flat 2D piece
+// sets of online boards: they are rendered the same way every time.
+
+use crate::model_info::PIECE_MAP;
+use crate::recognizer::{square_rect, BoardProbabilities, Recognizer, CLASS_COUNT, EMPTY};
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+
+/// Squares are compared at TEMPLATE_SIZE x TEMPLATE_SIZE grayscale pixels.
+const TEMPLATE_SIZE: u32 = 32;
+// largest mean difference (0-255) between an empty square and the empty template of its colour
+const EMPTY_TOLERANCE: f32 = 3.0;
+// largest difference of a pixel from the empty square that still counts as background
+const BACKGROUND_TOLERANCE: u8 = 12;
+// how fast the probability of a template drops with its difference to the square
+const TEMPERATURE: f32 = 2.0;
+
+// The starting position in screen order as seen by white, ' ' for empty squares.
+const START_POSITION: [&str; 8] = [
+    "rnbqkbnr", "pppppppp", "        ", "        ", "        ", "        ", "PPPPPPPP", "RNBQKBNR",
+];
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TemplateLibrary {
+    templates: Vec<PieceTemplate>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct PieceTemplate {
+    // None for an empty square
+    piece: Option<char>,
+    light_square: bool,
+    pixels: Vec<u8>,
+}
+
+impl TemplateLibrary {
+    /// Captures the pieces and the empty squares of a screenshot of the starting position, the
+    /// board being at `board` (x, y, width, height).
+    pub fn capture(image: &DynamicImage, board: [f32; 4], white_pov: bool) -> Result<Self> {
+        // every (piece, square colour) seen, with the sum of its pixels and how many were added
+        let mut sums: Vec<(Option<char>, bool, Vec<u32>, u32)> = Vec::new();
+        for row in 0..8 {
+            for column in 0..8 {
+                let pixels = square_pixels(image, board, row, column)?;
+                let piece = start_piece(row, column, white_pov);
+                let light_square = (row + column) % 2 == 0;
+
+                let index = match sums
+                    .iter()
+                    .position(|(p, light, _, _)| *p == piece && *light == light_square)
+                {
+                    Some(index) => index,
+                    None => {
+                        sums.push((piece, light_square, vec![0; pixels.len()], 0));
+                        sums.len() - 1
+                    }
+                };
+                let (_, _, sum, count) = &mut sums[index];
+                for (sum, pixel) in sum.iter_mut().zip(&pixels) {
+                    *sum += *pixel as u32;
+                }
+                *count += 1;
+            }
+        }
+
+        let templates = sums
+            .into_iter()
+            .map(|(piece, light_square, sum, count)| PieceTemplate {
+                piece,
+                light_square,
+                pixels: sum.iter().map(|sum| (sum / count) as u8).collect(),
+            })
+            .collect();
+        let mut library = Self { templates };
+        library.check_start_position(image, board, white_pov)?;
+        library.add_missing_square_colours();
+        Ok(library)
+    }
+
+    /// Fails unless the ranks in the middle are empty and the others hold pieces, which catches
+    /// screenshots of another position and boxes that aren't on the board.
+    fn check_start_position(
+        &self,
+        image: &DynamicImage,
+        board: [f32; 4],
+        white_pov: bool,
+    ) -> Result<()> {
+        for row in 0..8 {
+            for column in 0..8 {
+                let pixels = square_pixels(image, board, row, column)?;
+                let light_square = (row + column) % 2 == 0;
+                let empty = self
+                    .find(None, light_square)
+                    .context("The board has no empty squares")?;
+                let looks_empty = difference(&pixels, &empty.pixels) <= EMPTY_TOLERANCE;
+                let is_empty = start_piece(row, column, white_pov).is_none();
+
+                if looks_empty != is_empty {
+                    return Err(anyhow!(
+                        "The square on row {} and column {} {}, is the starting position on the screenshot{}?",
+                        row + 1,
+                        column + 1,
+                        if is_empty { "isn't empty" } else { "looks empty" },
+                        if white_pov { " with white at the bottom" } else { " with black at the bottom" },
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The queens and kings only stand on one square colour at the start, so their templates on
+    /// the other colour are made by swapping the background of the ones that were captured.
+    fn add_missing_square_colours(&mut self) {
+        let mut added = Vec::new();
+        for template in &self.templates {
+            let Some(piece) = template.piece else {
+                continue;
+            };
+            if self.find(Some(piece), !template.light_square).is_some() {
+                continue;
+            }
+            let (Some(background), Some(other_background)) = (
+                self.find(None, template.light_square),
+                self.find(None, !template.light_square),
+            ) else {
+                continue;
+            };
+
+            let pixels = template
+                .pixels
+                .iter()
+                .zip(background.pixels.iter().zip(&other_background.pixels))
+                .map(|(&pixel, (&background, &other_background))| {
+                    if pixel.abs_diff(background) <= BACKGROUND_TOLERANCE {
+                        other_background
+                    } else {
+                        pixel
+                    }
+                })
+                .collect();
+            added.push(PieceTemplate {
+                piece: Some(piece),
+                light_square: !template.light_square,
+                pixels,
+            });
+        }
+        self.templates.extend(added);
+    }
+
+    fn find(&self, piece: Option<char>, light_square: bool) -> Option<&PieceTemplate> {
+        self.templates
+            .iter()
+            .find(|template| template.piece == piece && template.light_square == light_square)
+    }
+}
+
+pub struct TemplateRecognizer {
+    library: TemplateLibrary,
+}
+
+impl TemplateRecognizer {
+    pub fn new(library: TemplateLibrary) -> Self {
+        Self { library }
+    }
+}
+
+impl Recognizer for TemplateRecognizer {
+    fn recognize(&self, image: &DynamicImage, board: [f32; 4]) -> Result<BoardProbabilities> {
+        let mut probabilities = BoardProbabilities::empty();
+        for (row, squares) in probabilities.squares.iter_mut().enumerate() {
+            for (column, square) in squares.iter_mut().enumerate() {
+                let pixels = square_pixels(image, board, row, column)?;
+
+                // the closest template of every class, whatever the colour of the square
+                let mut distances = [f32::MAX; CLASS_COUNT];
+                for template in &self.library.templates {
+                    let class = match template.piece {
+                        Some(piece) => match PIECE_MAP.iter().position(|&p| p == piece) {
+                            Some(class) => class,
+                            None => continue,
+                        },
+                        None => EMPTY,
+                    };
+                    distances[class] = distances[class].min(difference(&pixels, &template.pixels));
+                }
+
+                let closest = distances.iter().copied().fold(f32::MAX, f32::min);
+                for (probability, distance) in square.iter_mut().zip(distances) {
+                    *probability = (-(distance - closest) / TEMPERATURE).exp();
+                }
+                let total: f32 = square.iter().sum();
+                square
+                    .iter_mut()
+                    .for_each(|probability| *probability /= total);
+            }
+        }
+        Ok(probabilities)
+    }
+}
+
+/// The piece on a square of the starting position, in screen order.
+fn start_piece(row: usize, column: usize, white_pov: bool) -> Option<char> {
+    let (row, column) = if white_pov {
+        (row, column)
+    } else {
+        (7 - row, 7 - column)
+    };
+    Some(START_POSITION[row].as_bytes()[column] as char).filter(|&piece| piece != ' ')
+}
+
+/// The grayscale pixels of a square, leaving out a thin border so a pixel of error in the board's
+/// position doesn't bring in the neighbouring squares.
+fn square_pixels(
+    image: &DynamicImage,
+    board: [f32; 4],
+    row: usize,
+    column: usize,
+) -> Result<Vec<u8>> {
+    let [x, y, width, height] = square_rect(board, row, column);
+    let (inset_x, inset_y) = (width / 16.0, height / 16.0);
+    let (x, y) = ((x + inset_x).round(), (y + inset_y).round());
+    let (width, height) = (
+        (width - 2.0 * inset_x).round() as u32,
+        (height - 2.0 * inset_y).round() as u32,
+    );
+    if x < 0.0
+        || y < 0.0
+        || width == 0
+        || height == 0
+        || x as u32 + width > image.width()
+        || y as u32 + height > image.height()
+    {
+        return Err(anyhow!("The board is outside of the image"));
+    }
+
+    Ok(image
+        .crop_imm(x as u32, y as u32, width, height)
+        .resize_exact(TEMPLATE_SIZE, TEMPLATE_SIZE, FilterType::Triangle)
+        .into_luma8()
+        .into_raw())
+}
+
+/// Mean absolute difference of two sets of pixels.
+fn difference(a: &[u8], b: &[u8]) -> f32 {
+    let total: u32 = a.iter().zip(b).map(|(&a, &b)| a.abs_diff(b) as u32).sum();
+    total as f32 / a.len().max(1) as f32
+}
@@ -0,0 +1,213 @@
+// Smooths the detected position over several frames, so a single misdetected frame,
+// an animation or a dragged piece isn't taken for a move.
+
+use crate::recognizer::board_to_fen;
+use std::collections::VecDeque;
+
+struct Observation {
+    time_ms: u64,
+    squares: [[char; 8]; 8],
+    board: [f32; 4],
+}
+
+/// A position every square of which agreed across the window.
+pub struct StablePosition {
+    pub fen: String,
+    /// Time of the oldest frame in the window.
+    pub since_ms: u64,
+}
+
+pub struct TemporalFilter {
+    min_frames: usize,
+    min_duration_ms: u64,
+    // largest movement of the board between frames, relative to its size
+    max_board_shift: f32,
+
+    window: VecDeque<Observation>,
+}
+
+impl TemporalFilter {
+    /// With `min_frames` 1 and `min_duration_ms` 0 every frame is reported as is.
+    pub fn new(min_frames: usize, min_duration_ms: u64, max_board_shift: f32) -> Self {
+        Self {
+            min_frames: min_frames.max(1),
+            min_duration_ms,
+            max_board_shift,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Adds a frame's position (as FEN piece placement) and the board's bounding box (x, y, width, height).
+    ///
+    /// The window holds the last `min_frames` frames, extended back to cover `min_duration_ms`, and starts over
+    /// whenever the board moves. Once it is full, every square is voted on and the position is returned
+    /// if each square has a strict majority.
+    pub fn push(&mut self, time_ms: u64, fen: &str, board: [f32; 4]) -> Option<StablePosition> {
+        if self
+            .window
+            .back()
+            .is_some_and(|last| board_shift(&last.board, &board) > self.max_board_shift)
+        {
+            self.window.clear();
+        }
+
+        self.window.push_back(Observation {
+            time_ms,
+            squares: fen_to_squares(fen),
+            board,
+        });
+        while self.window.len() > self.min_frames
+            && time_ms.saturating_sub(self.window[1].time_ms) >= self.min_duration_ms
+        {
+            self.window.pop_front();
+        }
+
+        let since_ms = self.window.front()?.time_ms;
+        if self.window.len() < self.min_frames || time_ms - since_ms < self.min_duration_ms {
+            return None;
+        }
+
+        let mut squares = [[' '; 8]; 8];
+        for (rank, row) in squares.iter_mut().enumerate() {
+            for (file, square) in row.iter_mut().enumerate() {
+                *square = self.vote(rank, file)?;
+            }
+        }
+
+        Some(StablePosition {
+            fen: board_to_fen(&squares),
+            since_ms,
+        })
+    }
+
+    /// Forgets the frames seen so far.
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+
+    fn vote(&self, rank: usize, file: usize) -> Option<char> {
+        let mut votes: Vec<(char, usize)> = Vec::with_capacity(3);
+        for observation in &self.window {
+            let piece = observation.squares[rank][file];
+            match votes.iter_mut().find(|(candidate, _)| *candidate == piece) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((piece, 1)),
+            }
+        }
+
+        votes
+            .into_iter()
+            .find(|(_, count)| count * 2 > self.window.len())
+            .map(|(piece, _)| piece)
+    }
+}
+
+fn board_shift(previous: &[f32; 4], current: &[f32; 4]) -> f32 {
+    let size = previous[2].max(previous[3]).max(1.0);
+    previous
+        .iter()
+        .zip(current)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f32::max)
+        / size
+}
+
+fn fen_to_squares(fen: &str) -> [[char; 8]; 8] {
+    let mut squares = [[' '; 8]; 8];
+    for (rank, row) in fen.split('/').take(8).enumerate() {
+        let mut file = 0;
+        for c in row.chars() {
+            if let Some(empty) = c.to_digit(10) {
+                file += empty as usize;
+            } else if file < 8 {
+                squares[rank][file] = c;
+                file += 1;
+            }
+        }
+    }
+    squares
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOARD: [f32; 4] = [10.0, 10.0, 400.0, 400.0];
+    const START: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR";
+    const AFTER_E4: &str = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR";
+
+    #[test]
+    fn reports_every_frame_with_a_single_frame_window() {
+        let mut filter = TemporalFilter::new(1, 0, 0.05);
+        assert_eq!(filter.push(0, START, BOARD).unwrap().fen, START);
+        assert_eq!(filter.push(10, AFTER_E4, BOARD).unwrap().fen, AFTER_E4);
+    }
+
+    #[test]
+    fn waits_for_enough_frames() {
+        let mut filter = TemporalFilter::new(3, 0, 0.05);
+        assert!(filter.push(0, START, BOARD).is_none());
+        assert!(filter.push(10, START, BOARD).is_none());
+        let stable = filter.push(20, START, BOARD).unwrap();
+        assert_eq!(stable.fen, START);
+        assert_eq!(stable.since_ms, 0);
+    }
+
+    #[test]
+    fn outvotes_a_misread_frame() {
+        let mut filter = TemporalFilter::new(3, 0, 0.05);
+        filter.push(0, START, BOARD);
+        filter.push(10, AFTER_E4, BOARD);
+        assert_eq!(filter.push(20, START, BOARD).unwrap().fen, START);
+    }
+
+    #[test]
+    fn has_no_position_while_squares_disagree() {
+        let mut filter = TemporalFilter::new(2, 0, 0.05);
+        filter.push(0, START, BOARD);
+        assert!(filter.push(10, AFTER_E4, BOARD).is_none());
+        assert_eq!(filter.push(20, AFTER_E4, BOARD).unwrap().fen, AFTER_E4);
+    }
+
+    #[test]
+    fn waits_for_the_minimum_duration() {
+        let mut filter = TemporalFilter::new(1, 100, 0.05);
+        assert!(filter.push(0, START, BOARD).is_none());
+        assert!(filter.push(50, START, BOARD).is_none());
+        let stable = filter.push(100, START, BOARD).unwrap();
+        assert_eq!(stable.since_ms, 0);
+        // the window only keeps what covers the duration
+        assert_eq!(filter.push(180, START, BOARD).unwrap().since_ms, 50);
+    }
+
+    #[test]
+    fn starts_over_when_the_board_moves() {
+        let mut filter = TemporalFilter::new(2, 0, 0.05);
+        filter.push(0, START, BOARD);
+        let moved = [40.0, 10.0, 400.0, 400.0];
+        assert!(filter.push(10, START, moved).is_none());
+        assert!(filter.push(20, START, moved).is_some());
+
+        // a pixel or two is not a move
+        let nudged = [41.0, 11.0, 400.0, 400.0];
+        assert!(filter.push(30, START, nudged).is_some());
+    }
+
+    #[test]
+    fn forgets_frames_on_reset() {
+        let mut filter = TemporalFilter::new(2, 0, 0.05);
+        filter.push(0, START, BOARD);
+        filter.reset();
+        assert!(filter.push(10, START, BOARD).is_none());
+    }
+
+    #[test]
+    fn reads_fen_placements() {
+        let squares = fen_to_squares(AFTER_E4);
+        assert_eq!(squares[4][4], 'P');
+        assert_eq!(squares[6][4], ' ');
+        assert_eq!(board_to_fen(&squares), AFTER_E4);
+        // rows that are too long don't spill over
+        assert_eq!(fen_to_squares("ppppppppp/8/8/8/8/8/8/8")[0], ['p'; 8]);
+    }
+}
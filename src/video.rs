@@ -0,0 +1,354 @@
+// Turns a recorded game (a video file or a directory of frames) into a move list.
+
+use crate::arg_parser::{Args, Pov};
+use crate::chess_detection::{get_best_chessboard_match, ChessDetection, DetectionLevel};
+use crate::input_capture::replay::{collect_frames, extract_video_frames};
+use crate::temporal::TemporalFilter;
+use anyhow::{anyhow, Context, Result};
+use imageproc::image::{self, DynamicImage};
+use shakmaty::fen::Fen;
+use shakmaty::san::SanPlus;
+use shakmaty::{Board, CastlingMode, Chess, Color, EnPassantMode, Move, Position, PositionError};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// most moves we try to explain between two stable positions, in case sampling skipped some
+const MAX_MISSED_PLIES: usize = 2;
+
+/// One game consistent with the positions seen so far. A game not starting from the initial
+/// position could have either side to move, so both are followed until a move decides it.
+struct Line {
+    start: Chess,
+    position: Chess,
+    moves: Vec<SanPlus>,
+}
+
+struct TimelineEntry {
+    time_ms: u64,
+    fen: String,
+    // empty for the first position, "?" when no legal move explains the position
+    moves: Vec<String>,
+}
+
+pub fn video(
+    source: &str,
+    fps: f32,
+    temporal_filter: TemporalFilter,
+    pgn_path: Option<String>,
+    timeline_path: Option<String>,
+
+    args: &Args,
+    chess_detector: &ChessDetection,
+) -> Result<()> {
+    let source_path = Path::new(source);
+    let (frames, extracted_dir) = if source_path.is_dir() {
+        (collect_frames(source_path), None)
+    } else if source_path.is_file() {
+        let dir = extract_video_frames(source_path, Some(fps))?;
+        (collect_frames(dir.path()), Some(dir))
+    } else {
+        return Err(anyhow!("Video source `{}` does not exist", source));
+    };
+
+    let result = frames.and_then(|frames| {
+        process_frames(
+            &frames,
+            fps,
+            temporal_filter,
+            timeline_path.is_none(),
+            args,
+            chess_detector,
+        )
+    });
+    drop(extracted_dir);
+    let (lines, timeline) = result?;
+    let line = lines
+        .first()
+        .context("No stable chessboard position found")?;
+
+    if let Some(timeline_path) = timeline_path {
+        let mut file =
+            File::create(&timeline_path).context(format!("Failed to create {}", timeline_path))?;
+        writeln!(file, "time_ms\tfen\tmove")?;
+        for entry in &timeline {
+            write_timeline_entry(&mut file, entry)?;
+        }
+    }
+
+    let pgn = to_pgn(line, source);
+    match pgn_path {
+        Some(pgn_path) => {
+            fs::write(&pgn_path, pgn).context(format!("Failed to write {}", pgn_path))?
+        }
+        None => io::stdout().write_all(pgn.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+fn process_frames(
+    frames: &[PathBuf],
+    fps: f32,
+    mut temporal_filter: TemporalFilter,
+    print_timeline: bool,
+    args: &Args,
+    chess_detector: &ChessDetection,
+) -> Result<(Vec<Line>, Vec<TimelineEntry>)> {
+    let detection_level = if args.refined_search {
+        DetectionLevel::Refined
+    } else {
+        DetectionLevel::Basic
+    };
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut timeline = Vec::new();
+
+    // the last stable position handled, so each is handled once
+    let mut last_board: Option<Board> = None;
+
+    for (index, frame) in frames.iter().enumerate() {
+        let time_ms = (index as f32 * 1000.0 / fps) as u64;
+        let image =
+            image::open(frame).context(format!("Failed to load frame {}", frame.display()))?;
+
+        let Some((fen, board)) = detect_board(&image, &detection_level, args, chess_detector)?
+        else {
+            continue;
+        };
+        let Some(stable) = temporal_filter.push(time_ms, &fen, board) else {
+            continue;
+        };
+        let since_ms = stable.since_ms;
+        let Ok(board) = Board::from_ascii_board_fen(stable.fen.as_bytes()) else {
+            continue;
+        };
+        if last_board.as_ref() == Some(&board) {
+            continue;
+        }
+        last_board = Some(board.clone());
+
+        let entry = if lines.is_empty() {
+            lines = start_lines(&board);
+            if lines.is_empty() {
+                // not a legal position, e.g. a misdetected king
+                continue;
+            }
+            TimelineEntry {
+                time_ms: since_ms,
+                fen: full_fen(&lines[0].position),
+                moves: Vec::new(),
+            }
+        } else if lines[0].position.board() == &board {
+            continue;
+        } else {
+            match advance_lines(&mut lines, &board) {
+                Some(moves) => TimelineEntry {
+                    time_ms: since_ms,
+                    fen: full_fen(&lines[0].position),
+                    moves,
+                },
+                None => {
+                    eprintln!(
+                        "No legal move leads to the position at {} ms, ignoring it",
+                        since_ms
+                    );
+                    TimelineEntry {
+                        time_ms: since_ms,
+                        fen: board.to_string(),
+                        moves: vec!["?".to_string()],
+                    }
+                }
+            }
+        };
+
+        if print_timeline {
+            write_timeline_entry(&mut io::stdout(), &entry)?;
+        }
+        timeline.push(entry);
+    }
+
+    Ok((lines, timeline))
+}
+
+/// Returns the piece placement and the board's bounding box.
+fn detect_board(
+    image: &DynamicImage,
+    detection_level: &DetectionLevel,
+    args: &Args,
+    chess_detector: &ChessDetection,
+) -> Result<Option<(String, [f32; 4])>> {
+    let Some(detections) = chess_detector
+        .detect(image, detection_level)
+        .context("Detection failed")?
+    else {
+        return Ok(None);
+    };
+    let Some((best_match, _)) = get_best_chessboard_match(&detections) else {
+        return Ok(None);
+    };
+
+    let fen = chess_detector.output_to_fen(
+        image,
+        &detections,
+        [best_match[0], best_match[1], best_match[2], best_match[3]],
+        args.pov == Pov::W,
+    )?;
+    Ok(Some((
+        fen,
+        [best_match[0], best_match[1], best_match[2], best_match[3]],
+    )))
+}
+
+/// Games that could start from `board`.
+fn start_lines(board: &Board) -> Vec<Line> {
+    if board == Chess::default().board() {
+        return vec![new_line(Chess::default())];
+    }
+
+    [Color::White, Color::Black]
+        .into_iter()
+        .filter_map(|turn| {
+            // castling rights are kept where the king and rooks are still in place
+            let fen = format!(
+                "{} {} KQkq - 0 1",
+                board,
+                if turn == Color::White { "w" } else { "b" }
+            );
+            Fen::from_ascii(fen.as_bytes())
+                .ok()?
+                .into_position::<Chess>(CastlingMode::Standard)
+                .or_else(PositionError::ignore_invalid_castling_rights)
+                .ok()
+        })
+        .map(new_line)
+        .collect()
+}
+
+fn new_line(position: Chess) -> Line {
+    Line {
+        start: position.clone(),
+        position,
+        moves: Vec::new(),
+    }
+}
+
+/// Plays the moves leading to `board` on every line that has them and drops the others.
+/// Returns the moves of the first remaining line, None (leaving the lines alone) if no line has any.
+fn advance_lines(lines: &mut Vec<Line>, board: &Board) -> Option<Vec<String>> {
+    let mut advanced: Vec<(Line, Vec<String>)> = Vec::new();
+    for line in lines.iter() {
+        if let Some(moves) = find_moves(&line.position, board, MAX_MISSED_PLIES) {
+            let mut position = line.position.clone();
+            let sans: Vec<SanPlus> = moves
+                .iter()
+                .map(|m| SanPlus::from_move_and_play_unchecked(&mut position, m))
+                .collect();
+            let played = sans.iter().map(|san| san.to_string()).collect();
+
+            let mut line_moves = line.moves.clone();
+            line_moves.extend(sans);
+            advanced.push((
+                Line {
+                    start: line.start.clone(),
+                    position,
+                    moves: line_moves,
+                },
+                played,
+            ));
+        }
+    }
+
+    let played = advanced.first()?.1.clone();
+    *lines = advanced.into_iter().map(|(line, _)| line).collect();
+    Some(played)
+}
+
+/// Shortest sequence of at most `max_plies` legal moves from `position` to `board`.
+fn find_moves(position: &Chess, board: &Board, max_plies: usize) -> Option<Vec<Move>> {
+    let mut frontier = vec![(position.clone(), Vec::new())];
+    for _ in 0..max_plies {
+        let mut next = Vec::new();
+        for (position, moves) in frontier {
+            for m in position.legal_moves() {
+                let mut after = position.clone();
+                after.play_unchecked(&m);
+                let mut moves: Vec<Move> = moves.clone();
+                moves.push(m);
+                if after.board() == board {
+                    return Some(moves);
+                }
+                next.push((after, moves));
+            }
+        }
+        frontier = next;
+    }
+    None
+}
+
+fn full_fen(position: &Chess) -> String {
+    Fen::from_position(position.clone(), EnPassantMode::Legal).to_string()
+}
+
+fn write_timeline_entry(out: &mut impl Write, entry: &TimelineEntry) -> Result<()> {
+    writeln!(
+        out,
+        "{}\t{}\t{}",
+        entry.time_ms,
+        entry.fen,
+        entry.moves.join(" ")
+    )?;
+    Ok(())
+}
+
+fn to_pgn(line: &Line, source: &str) -> String {
+    let mut pgn = String::new();
+    pgn.push_str("[Event \"?\"]\n");
+    pgn.push_str(&format!(
+        "[Site \"{}\"]\n",
+        source.replace('\\', "\\\\").replace('"', "\\\"")
+    ));
+    pgn.push_str("[Date \"????.??.??\"]\n[Round \"?\"]\n[White \"?\"]\n[Black \"?\"]\n");
+    pgn.push_str("[Result \"*\"]\n");
+    if full_fen(&line.start) != full_fen(&Chess::default()) {
+        pgn.push_str(&format!(
+            "[SetUp \"1\"]\n[FEN \"{}\"]\n",
+            full_fen(&line.start)
+        ));
+    }
+    pgn.push('\n');
+
+    let mut tokens = Vec::new();
+    let mut move_number = line.start.fullmoves().get();
+    let mut turn = line.start.turn();
+    for (index, san) in line.moves.iter().enumerate() {
+        if turn == Color::White {
+            tokens.push(format!("{}.", move_number));
+        } else if index == 0 {
+            tokens.push(format!("{}...", move_number));
+        }
+        tokens.push(san.to_string());
+
+        if turn == Color::Black {
+            move_number += 1;
+        }
+        turn = !turn;
+    }
+    tokens.push("*".to_string());
+
+    // PGN lines should stay below 80 characters
+    let mut width = 0;
+    for token in tokens {
+        if width > 0 && width + token.len() + 1 > 79 {
+            pgn.push('\n');
+            width = 0;
+        } else if width > 0 {
+            pgn.push(' ');
+            width += 1;
+        }
+        width += token.len();
+        pgn.push_str(&token);
+    }
+    pgn.push('\n');
+    pgn
+}
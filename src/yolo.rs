@@ -0,0 +1,182 @@
+// Decoding of the raw YOLOv8/v11 detection head, for models exported without `nms=True`.
+
+use ndarray::{Array2, ArrayViewD, Axis};
+
+// like the NMS Ultralytics adds on export: boxes from this confidence (or --conf if it's lower)
+// are candidates for NMS, and at most MAX_DETECTIONS of them are kept
+const CANDIDATE_CONFIDENCE: f32 = 0.25;
+const MAX_DETECTIONS: usize = 300;
+
+/// Turns the head's output of shape [1, 4 + classes, anchors] into detections of shape
+/// [count, 6] (x1, y1, x2, y2, confidence, class), like the ones of a model exported with NMS.
+pub fn decode_raw_head(
+    output: ArrayViewD<f32>,
+    confidence_threshold: f32,
+    iou_threshold: f32,
+) -> Array2<f32> {
+    let confidence_threshold = confidence_threshold.min(CANDIDATE_CONFIDENCE);
+    let output = match output.ndim() {
+        3 => output.index_axis_move(Axis(0), 0),
+        _ => output,
+    };
+
+    let mut candidates: Vec<[f32; 6]> = Vec::new();
+    if output.ndim() == 2 && output.shape()[0] > 4 {
+        for anchor in output.axis_iter(Axis(1)) {
+            let Some((class, &confidence)) = anchor
+                .iter()
+                .skip(4)
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+            else {
+                continue;
+            };
+            if confidence < confidence_threshold {
+                continue;
+            }
+
+            let (x, y, half_width, half_height) =
+                (anchor[0], anchor[1], anchor[2] / 2.0, anchor[3] / 2.0);
+            candidates.push([
+                x - half_width,
+                y - half_height,
+                x + half_width,
+                y + half_height,
+                confidence,
+                class as f32,
+            ]);
+        }
+    }
+
+    let detections = non_max_suppression(candidates, iou_threshold);
+    Array2::from_shape_vec(
+        (detections.len(), 6),
+        detections.into_iter().flatten().collect(),
+    )
+    .expect("six values per detection")
+}
+
+/// Keeps the most confident of the boxes of a class that overlap by more than `iou_threshold`.
+fn non_max_suppression(mut candidates: Vec<[f32; 6]>, iou_threshold: f32) -> Vec<[f32; 6]> {
+    candidates.sort_by(|a, b| b[4].total_cmp(&a[4]));
+
+    let mut kept: Vec<[f32; 6]> = Vec::new();
+    for candidate in candidates {
+        if kept.len() == MAX_DETECTIONS {
+            break;
+        }
+        if kept
+            .iter()
+            .all(|other| other[5] != candidate[5] || iou(other, &candidate) <= iou_threshold)
+        {
+            kept.push(candidate);
+        }
+    }
+    kept
+}
+
+fn iou(a: &[f32; 6], b: &[f32; 6]) -> f32 {
+    let width = (a[2].min(b[2]) - a[0].max(b[0])).max(0.0);
+    let height = (a[3].min(b[3]) - a[1].max(b[1])).max(0.0);
+    let intersection = width * height;
+    let union = (a[2] - a[0]) * (a[3] - a[1]) + (b[2] - b[0]) * (b[3] - b[1]) - intersection;
+    if union <= 0.0 {
+        0.0
+    } else {
+        intersection / union
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    /// A head of shape [1, 4 + classes, anchors] from (center x, center y, width, height, scores).
+    fn head(anchors: &[(f32, f32, f32, f32, &[f32])]) -> Array3<f32> {
+        let classes = anchors[0].4.len();
+        let mut head = Array3::zeros((1, 4 + classes, anchors.len()));
+        for (index, &(x, y, width, height, scores)) in anchors.iter().enumerate() {
+            for (row, value) in [x, y, width, height].iter().chain(scores).enumerate() {
+                head[[0, row, index]] = *value;
+            }
+        }
+        head
+    }
+
+    #[test]
+    fn decodes_boxes_to_corners() {
+        let head = head(&[(50.0, 40.0, 20.0, 10.0, &[0.1, 0.8])]);
+        let detections = decode_raw_head(head.view().into_dyn(), 0.5, 0.7);
+        assert_eq!(
+            detections.row(0).to_vec(),
+            [40.0, 35.0, 60.0, 45.0, 0.8, 1.0]
+        );
+    }
+
+    #[test]
+    fn accepts_a_head_without_the_batch_axis() {
+        let head = head(&[(50.0, 40.0, 20.0, 10.0, &[0.9])]);
+        let head = head.index_axis(Axis(0), 0).into_dyn();
+        assert_eq!(decode_raw_head(head, 0.5, 0.7).nrows(), 1);
+    }
+
+    #[test]
+    fn keeps_candidates_down_to_the_lower_threshold() {
+        let head = head(&[
+            (10.0, 10.0, 10.0, 10.0, &[0.2]),
+            (30.0, 30.0, 10.0, 10.0, &[0.3]),
+            (50.0, 50.0, 10.0, 10.0, &[0.6]),
+        ]);
+        // --conf above the candidate cut-off still lets the candidates through
+        assert_eq!(decode_raw_head(head.view().into_dyn(), 0.5, 0.7).nrows(), 2);
+        assert_eq!(decode_raw_head(head.view().into_dyn(), 0.1, 0.7).nrows(), 3);
+    }
+
+    #[test]
+    fn suppresses_overlapping_boxes_of_a_class() {
+        let head = head(&[
+            (50.0, 50.0, 20.0, 20.0, &[0.7, 0.0]),
+            (51.0, 50.0, 20.0, 20.0, &[0.9, 0.0]),
+            (90.0, 90.0, 20.0, 20.0, &[0.6, 0.0]),
+        ]);
+        let detections = decode_raw_head(head.view().into_dyn(), 0.5, 0.7);
+        assert_eq!(detections.nrows(), 2);
+        // the most confident of the overlapping pair, then the separate box
+        assert_eq!(detections[[0, 4]], 0.9);
+        assert_eq!(detections[[1, 4]], 0.6);
+    }
+
+    #[test]
+    fn keeps_overlapping_boxes_of_other_classes() {
+        let head = head(&[
+            (50.0, 50.0, 20.0, 20.0, &[0.9, 0.0]),
+            (50.0, 50.0, 20.0, 20.0, &[0.0, 0.8]),
+        ]);
+        let detections = decode_raw_head(head.view().into_dyn(), 0.5, 0.7);
+        assert_eq!(detections.nrows(), 2);
+        assert_eq!(detections.column(5).to_vec(), [0.0, 1.0]);
+    }
+
+    #[test]
+    fn keeps_at_most_max_detections() {
+        let scores = [0.9];
+        let anchors: Vec<_> = (0..MAX_DETECTIONS + 10)
+            .map(|index| (index as f32 * 20.0, 0.0, 10.0, 10.0, &scores[..]))
+            .collect();
+        let head = head(&anchors);
+        assert_eq!(
+            decode_raw_head(head.view().into_dyn(), 0.5, 0.7).nrows(),
+            MAX_DETECTIONS
+        );
+    }
+
+    #[test]
+    fn ignores_outputs_that_are_not_a_head() {
+        let output = Array3::<f32>::zeros((1, 4, 10));
+        assert_eq!(
+            decode_raw_head(output.view().into_dyn(), 0.5, 0.7).nrows(),
+            0
+        );
+    }
+}